@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-const SERVICE_NAME: &'static str = "gaimoded.service";
+const SERVICE_NAME: &str = "gaimoded.service";
 
 pub fn check_or_spin_up_daemon() -> anyhow::Result<()> {
     let conn = dbus::blocking::Connection::new_system()?;
@@ -1,16 +1,16 @@
 use std::{
     ffi::{CStr, CString},
     io::Write,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
     str::FromStr,
 };
 
 use clap::{Parser, Subcommand};
-use gaiproto::Gaiproto;
+use gaiproto::Message;
 use nix::{sys::wait::waitpid, unistd};
 
 mod dbus_i;
-
-const UDS_FILENAME: &'static str = "gaimoded_sock";
+mod status;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -19,39 +19,84 @@ struct Args {
 
     #[arg(long)]
     forked: bool,
+
+    #[arg(long, env = gaiproto::SOCKET_PATH_ENV, default_value = gaiproto::DEFAULT_SOCKET_PATH)]
+    socket: std::path::PathBuf,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     #[command(arg_required_else_help = true)]
     Run {
+        // Settings profile to use instead of the one the daemon would pick
+        #[arg(long)]
+        profile: Option<String>,
         #[arg(value_name = "Binary path")]
         executable: String,
         #[arg(value_name = "Arguments for binary")]
         args: Vec<String>,
     },
+    // Optimize already running processes, e.g. everything a Proton prefix spawned
+    #[command(arg_required_else_help = true)]
+    Optimize {
+        #[arg(value_name = "Process ID", required = true)]
+        pids: Vec<i32>,
+    },
     #[command(arg_required_else_help = true)]
     ResetProcess {
+        #[arg(value_name = "Process ID", required = true)]
+        pids: Vec<i32>,
+    },
+    ResetAll,
+    // Show processes and system settings the daemon currently manages
+    Status,
+    // Print what the daemon does as it happens
+    Events,
+    // Inspect the daemon's automatic game detection
+    WatchRules {
+        #[command(subcommand)]
+        command: WatchRulesCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum WatchRulesCommands {
+    // Show which rule a running process matches
+    #[command(arg_required_else_help = true)]
+    Test {
         #[arg(value_name = "Process ID")]
         pid: i32,
     },
-    ResetAll,
 }
 
 fn run(
     bin_name: String,
     args: Vec<String>,
+    profile: Option<String>,
     mut stream: std::os::unix::net::UnixStream,
 ) -> anyhow::Result<()> {
     match unsafe { unistd::fork() } {
         Ok(unistd::ForkResult::Parent { child }) => {
-            let packet = Gaiproto::new(
-                (gaiproto::MIN_PACKET_SIZE + std::mem::size_of_val(&child)) as u32,
-                gaiproto::K_OPTIMIZE_PROCESS,
-                child.as_raw().to_be_bytes().to_vec(),
-            );
-            let bytes = packet.convert_to_bytes();
-            stream.write_all(&bytes)?;
+            // The child can't be reaped before we wait for it, so this pidfd is
+            // guaranteed to name it. Kernels without pidfd_open get the plain PID.
+            match pidfd_open(child) {
+                Ok(pidfd) => {
+                    let message = Message::OptimizeProcessFd {
+                        pid: child.as_raw(),
+                        profile,
+                    };
+                    send_message_with_fd(&mut stream, &message, &pidfd)?;
+                }
+                Err(why) => {
+                    eprintln!("Sending plain PID, no pidfd: {}", why);
+                    let message = Message::OptimizeProcess {
+                        pid: child.as_raw(),
+                        profile,
+                    };
+                    send_message(&mut stream, &message)?;
+                }
+            }
+            report(read_response(&mut stream)?);
 
             if let Err(why) = waitpid(child, None) {
                 eprintln!("Failed to wait for child: {}", why);
@@ -82,58 +127,311 @@ fn run(
     Ok(())
 }
 
-fn reset_process(pid: i32, mut stream: std::os::unix::net::UnixStream) -> anyhow::Result<()> {
-    let packet = Gaiproto::new(
-        (gaiproto::MIN_PACKET_SIZE + std::mem::size_of_val(&pid)) as u32,
-        gaiproto::K_RESET_PROCESS,
-        pid.to_be_bytes().to_vec(),
-    );
-    let bytes = packet.convert_to_bytes();
-    stream.write_all(&bytes)?;
+fn optimize(pids: Vec<i32>, mut stream: std::os::unix::net::UnixStream) -> anyhow::Result<()> {
+    send_message(&mut stream, &Message::OptimizeBatch { pids })?;
+    report_batch(&mut stream)
+}
+
+fn reset_process(
+    mut pids: Vec<i32>,
+    mut stream: std::os::unix::net::UnixStream,
+) -> anyhow::Result<()> {
+    if pids.len() == 1 {
+        let pid = pids.remove(0);
+        send_message(&mut stream, &Message::ResetProcess { pid })?;
+        report(read_response(&mut stream)?);
+        return Ok(());
+    }
+    send_message(&mut stream, &Message::ResetBatch { pids })?;
+    report_batch(&mut stream)
+}
+
+fn report_batch(stream: &mut std::os::unix::net::UnixStream) -> anyhow::Result<()> {
+    match read_message(stream)? {
+        Message::BatchResult(results) => {
+            for result in results {
+                println!("Process {}:", result.pid);
+                report(result.response);
+            }
+        }
+        Message::Response(response) => report(response),
+        other => return Err(anyhow::anyhow!("Unexpected reply from daemon: {:?}", other)),
+    }
     Ok(())
 }
 
 fn reset_all(mut stream: std::os::unix::net::UnixStream) -> anyhow::Result<()> {
-    let packet = Gaiproto::new(
-        gaiproto::MIN_PACKET_SIZE as u32,
-        gaiproto::K_RESET_ALL,
-        Vec::new(),
-    );
-    let bytes = packet.convert_to_bytes();
-    stream.write_all(&bytes)?;
+    send_message(&mut stream, &Message::ResetAll)?;
+    report(read_response(&mut stream)?);
+    Ok(())
+}
+
+fn status(mut stream: std::os::unix::net::UnixStream, version: u16) -> anyhow::Result<()> {
+    send_message(&mut stream, &Message::StatusRequest)?;
+    // The report's layout depends on the version the daemon speaks
+    let packet = gaiproto::GaiprotoCodec::new().read_from(&mut stream)?;
+    match Message::decode_for(&packet, version)? {
+        Message::Status(report) => print!("{}", status::render(&report)),
+        Message::Response(response) => report(response),
+        other => return Err(anyhow::anyhow!("Unexpected reply from daemon: {:?}", other)),
+    }
+    Ok(())
+}
+
+fn events(mut stream: std::os::unix::net::UnixStream) -> anyhow::Result<()> {
+    send_message(&mut stream, &Message::Subscribe)?;
+    if let gaiproto::Response::Error { code, message } = read_response(&mut stream)? {
+        return Err(anyhow::anyhow!(
+            "Daemon returned error {:#x}: {}",
+            code,
+            message
+        ));
+    }
+
+    loop {
+        match read_message(&mut stream)? {
+            Message::Event(event) => match event {
+                gaiproto::Event::ProcessOptimized { pid } => println!("optimized  {}", pid),
+                gaiproto::Event::ProcessReset { pid } => println!("reset      {}", pid),
+                gaiproto::Event::ProcessDied { pid } => println!("died       {}", pid),
+                gaiproto::Event::GovernorChanged { policy, governor } => {
+                    println!("governor   {} -> {}", policy, governor)
+                }
+            },
+            other => {
+                return Err(anyhow::anyhow!(
+                    "Unexpected message from daemon: {:?}",
+                    other
+                ));
+            }
+        }
+    }
+}
+
+fn watch_rules_test(pid: i32, mut stream: std::os::unix::net::UnixStream) -> anyhow::Result<()> {
+    send_message(&mut stream, &Message::WatchRulesTest { pid })?;
+    let report = match read_message(&mut stream)? {
+        Message::WatchReport(report) => report,
+        Message::Response(response) => {
+            report(response);
+            return Ok(());
+        }
+        other => return Err(anyhow::anyhow!("Unexpected reply from daemon: {:?}", other)),
+    };
+
+    println!("Process {}", report.pid);
+    println!("  exe:     {}", report.exe);
+    println!("  comm:    {}", report.comm);
+    println!("  cmdline: {}", report.cmdline);
+    match report.verdict {
+        gaiproto::WatchVerdict::Matched(rule) => println!("Matches rule '{}'", rule),
+        gaiproto::WatchVerdict::Excluded(rule) => println!("Excluded by rule '{}'", rule),
+        gaiproto::WatchVerdict::NoMatch => println!("No rule matches"),
+    }
+    if !report.watcher_enabled {
+        println!("The watcher is disabled, nothing is optimized automatically");
+    }
+    Ok(())
+}
+
+// Exchanges HELLO with the daemon, fails if it speaks an incompatible protocol
+fn handshake(stream: &mut std::os::unix::net::UnixStream) -> anyhow::Result<gaiproto::Hello> {
+    // A busy daemon answers right away and hangs up, so its reply is read even if sending failed
+    let sent = send_message(stream, &Message::Hello(gaiproto::Hello::new(0)));
+
+    let hello = match read_message(stream) {
+        Ok(Message::Hello(hello)) => hello,
+        Ok(Message::Response(gaiproto::Response::Error { code, message })) => {
+            let why = match code {
+                gaiproto::E_BUSY => "Daemon is busy, try again later",
+                _ => "Daemon refused connection",
+            };
+            return Err(anyhow::anyhow!("{}: {}", why, message));
+        }
+        Ok(_) => return Err(anyhow::anyhow!("Daemon did not answer HELLO")),
+        Err(why) => return Err(sent.err().unwrap_or(why)),
+    };
+    if !hello.is_compatible() {
+        return Err(anyhow::anyhow!(
+            "Daemon speaks protocol version {}, need at least {}",
+            hello.version,
+            gaiproto::MIN_SUPPORTED_VERSION
+        ));
+    }
+    Ok(hello)
+}
+
+fn warn_unsupported(hello: &gaiproto::Hello) {
+    let knobs = [
+        gaiproto::Knob::Niceness,
+        gaiproto::Knob::IoNiceness,
+        gaiproto::Knob::CpuAffinity,
+        gaiproto::Knob::CpuGovernor,
+    ];
+    let missing = knobs
+        .iter()
+        .filter(|knob| !hello.supports(**knob))
+        .map(|knob| knob.name())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        eprintln!("Daemon won't apply: {}", missing.join(", "));
+    }
+}
+
+fn read_response(
+    stream: &mut std::os::unix::net::UnixStream,
+) -> anyhow::Result<gaiproto::Response> {
+    match read_message(stream)? {
+        Message::Response(response) => Ok(response),
+        other => Err(anyhow::anyhow!("Unexpected reply from daemon: {:?}", other)),
+    }
+}
+
+fn read_message(stream: &mut std::os::unix::net::UnixStream) -> anyhow::Result<Message> {
+    let packet = gaiproto::GaiprotoCodec::new().read_from(stream)?;
+    Ok(Message::decode(&packet)?)
+}
+
+fn send_message(
+    stream: &mut std::os::unix::net::UnixStream,
+    message: &Message,
+) -> anyhow::Result<()> {
+    Ok(gaiproto::GaiprotoCodec::new().write_to(stream, message.encode())?)
+}
+
+// Same as send_message, with `fd` passed along as SCM_RIGHTS
+fn send_message_with_fd(
+    stream: &mut std::os::unix::net::UnixStream,
+    message: &Message,
+    fd: &OwnedFd,
+) -> anyhow::Result<()> {
+    use nix::sys::socket::{ControlMessage, MsgFlags, sendmsg};
+
+    let bytes = message.encode().convert_to_bytes();
+    let fds = [fd.as_raw_fd()];
+    let sent = sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[std::io::IoSlice::new(&bytes)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    // The descriptor went with the first chunk, the rest is ordinary data
+    stream.write_all(&bytes[sent..])?;
     Ok(())
 }
 
+fn pidfd_open(pid: unistd::Pid) -> anyhow::Result<OwnedFd> {
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as i32) })
+}
+
+fn report(response: gaiproto::Response) {
+    match response {
+        gaiproto::Response::Ok(knobs) => {
+            if knobs.is_empty() {
+                println!("Done");
+            } else {
+                let names = knobs.iter().map(|k| k.name()).collect::<Vec<_>>();
+                println!("Applied: {}", names.join(", "));
+            }
+        }
+        gaiproto::Response::Error { code, message } => {
+            eprintln!("Daemon returned error {:#x}: {}", code, message);
+        }
+        gaiproto::Response::Partial(results) => {
+            println!("Partially applied:");
+            for result in results {
+                match result.error {
+                    None => println!("  {}: applied", result.knob.name()),
+                    Some(why) => println!("  {}: failed ({})", result.knob.name(), why),
+                }
+            }
+        }
+    }
+}
+
 fn main() {
     // TODO: A way to avoid dealing with systemd daemons (using args), systemd is the default way
 
     let args = Args::parse();
-    let mut path = std::env::temp_dir();
-    path.push(UDS_FILENAME);
     // TODO: Check if dbus service is running if not start it
-    if !args.forked {
-        if let Err(why) = dbus_i::check_or_spin_up_daemon() {
-            eprintln!("Failed to check for a daemon: {}", why);
+    if !args.forked
+        && let Err(why) = dbus_i::check_or_spin_up_daemon()
+    {
+        eprintln!("Failed to check for a daemon: {}", why);
+        return;
+    }
+    let mut stream = match std::os::unix::net::UnixStream::connect(&args.socket) {
+        Ok(stream) => stream,
+        Err(why) => {
+            eprintln!(
+                "Could not connect to the daemon at {}: {}",
+                args.socket.display(),
+                why
+            );
             return;
         }
-    }
-    let stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+    };
+    let hello = match handshake(&mut stream) {
+        Ok(hello) => hello,
+        Err(why) => {
+            eprintln!("Handshake failed: {}", why);
+            return;
+        }
+    };
 
     match args.command {
-        Commands::Run { executable, args } => {
-            if let Err(why) = run(executable, args, stream) {
+        Commands::Run {
+            profile,
+            executable,
+            args,
+        } => {
+            if profile.is_some() && hello.negotiated() < gaiproto::PROFILES_VERSION {
+                eprintln!(
+                    "The daemon is too old to know about profiles, update it to use --profile"
+                );
+                return;
+            }
+            warn_unsupported(&hello);
+            if let Err(why) = run(executable, args, profile, stream) {
                 eprintln!("Could not run the process: {}", why);
             }
         }
-        Commands::ResetProcess { pid } => {
-            if let Err(why) = reset_process(pid, stream) {
+        Commands::Optimize { pids } => {
+            warn_unsupported(&hello);
+            if let Err(why) = optimize(pids, stream) {
+                eprintln!("Could not optimize the processes: {}", why);
+            }
+        }
+        Commands::ResetProcess { pids } => {
+            if let Err(why) = reset_process(pids, stream) {
                 eprintln!("Could not reset the process: {}", why);
             }
         }
-        #[allow(unused)]
         Commands::ResetAll => {
             if let Err(why) = reset_all(stream) {
-                eprintln!("Could not reset processes");
+                eprintln!("Could not reset processes: {}", why);
+            }
+        }
+        Commands::Status => {
+            if let Err(why) = status(stream, hello.negotiated()) {
+                eprintln!("Could not get status: {}", why);
+            }
+        }
+        Commands::Events => {
+            if let Err(why) = events(stream) {
+                eprintln!("Event stream ended: {}", why);
+            }
+        }
+        Commands::WatchRules {
+            command: WatchRulesCommands::Test { pid },
+        } => {
+            if let Err(why) = watch_rules_test(pid, stream) {
+                eprintln!("Could not test watcher rules: {}", why);
             }
         }
     }
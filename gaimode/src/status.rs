@@ -0,0 +1,71 @@
+use gaiproto::StatusReport;
+
+// Collapses a CPU list into ranges, e.g. [0, 1, 2, 5] -> "0-2,5"
+fn format_cpus(cpus: &[u32]) -> String {
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < cpus.len() {
+        let start = cpus[i];
+        let mut end = start;
+        while i + 1 < cpus.len() && cpus[i + 1] == end + 1 {
+            i += 1;
+            end = cpus[i];
+        }
+        if start == end {
+            parts.push(start.to_string());
+        } else {
+            parts.push(format!("{}-{}", start, end));
+        }
+        i += 1;
+    }
+    parts.join(",")
+}
+
+fn opt<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_owned(), |v| v.to_string())
+}
+
+pub fn render(report: &StatusReport) -> String {
+    let mut out = String::new();
+    out.push_str(&format!(
+        "Optimized: {}\n",
+        if report.is_optimized { "yes" } else { "no" }
+    ));
+
+    if !report.governors.is_empty() {
+        out.push_str("\nSaved governors:\n");
+        let width = report
+            .governors
+            .iter()
+            .map(|g| g.policy.len())
+            .max()
+            .unwrap_or(0)
+            .max("POLICY".len());
+        out.push_str(&format!("{:<width$}  GOVERNOR\n", "POLICY"));
+        for gov in &report.governors {
+            out.push_str(&format!("{:<width$}  {}\n", gov.policy, gov.governor));
+        }
+    }
+
+    if report.processes.is_empty() {
+        out.push_str("\nNo processes are optimized\n");
+        return out;
+    }
+
+    out.push_str(&format!(
+        "\n{:<8}  {:<12}  {:<5}  {:<6}  {:<6}  {}\n",
+        "PID", "PROFILE", "NICE", "IONICE", "PINNED", "AFFINITY"
+    ));
+    for process in &report.processes {
+        out.push_str(&format!(
+            "{:<8}  {:<12}  {:<5}  {:<6}  {:<6}  {}\n",
+            process.pid,
+            opt(process.profile.as_deref()),
+            opt(process.niceness),
+            opt(process.ioniceness),
+            opt(process.pinned_cpu),
+            opt(process.affinity.as_deref().map(format_cpus)),
+        ));
+    }
+    out
+}
@@ -0,0 +1,61 @@
+use std::{ffi::CString, os::unix::fs::MetadataExt};
+
+use nix::unistd::{Gid, Group, Pid, Uid, User};
+
+use crate::paths::SystemPaths;
+
+// Who is on the other end of a connection, taken from SO_PEERCRED
+pub struct Peer {
+    pub uid: Uid,
+    pub pid: Option<i32>,
+    // Root and members of the admin group may manage everyone's processes
+    pub is_admin: bool,
+}
+
+impl Peer {
+    pub fn from_stream(stream: &tokio::net::UnixStream, admin_group: &str) -> anyhow::Result<Peer> {
+        let cred = stream.peer_cred()?;
+        let uid = Uid::from_raw(cred.uid());
+        let is_admin = uid.is_root() || is_group_member(uid, admin_group).unwrap_or(false);
+        Ok(Peer {
+            uid,
+            pid: cred.pid(),
+            is_admin,
+        })
+    }
+
+    pub fn may_manage(&self, paths: &SystemPaths, pid: Pid) -> bool {
+        if self.is_admin {
+            return true;
+        }
+        // Unreadable or gone processes are refused, the optimizer would fail on them anyway
+        matches!(process_owner(paths, pid), Ok(owner) if owner == self.uid)
+    }
+
+    // Whose processes ResetAll may touch, None means all of them
+    pub fn scope(&self) -> Option<Uid> {
+        (!self.is_admin).then_some(self.uid)
+    }
+
+    pub fn denied(&self, pid: i32) -> gaiproto::Response {
+        tracing::warn!("Refused uid {} access to process {}", self.uid, pid);
+        gaiproto::Response::Error {
+            code: gaiproto::E_PERMISSION_DENIED,
+            message: format!("Process {} is not owned by you", pid),
+        }
+    }
+}
+
+pub fn process_owner(paths: &SystemPaths, pid: Pid) -> anyhow::Result<Uid> {
+    let meta = std::fs::metadata(paths.process(pid))?;
+    Ok(Uid::from_raw(meta.uid()))
+}
+
+fn is_group_member(uid: Uid, group: &str) -> anyhow::Result<bool> {
+    let Some(group) = Group::from_name(group)? else {
+        return Ok(false);
+    };
+    let user = User::from_uid(uid)?.ok_or_else(|| anyhow::anyhow!("Unknown uid {}", uid))?;
+    let groups: Vec<Gid> = nix::unistd::getgrouplist(&CString::new(user.name)?, user.gid)?;
+    Ok(groups.contains(&group.gid))
+}
@@ -1,38 +1,180 @@
+use std::collections::HashMap;
+
 use serde::Deserialize;
 
 use crate::{cpu, io, scheduler};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct CpuAffinity {
     pub enabled: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct CpuGovernor {
     pub enabled: bool,
     pub optimized_type: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Niceness {
     pub enabled: bool,
     pub optimized_value: i32,
     pub default_value: i32,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct IoNiceness {
     pub enabled: bool,
     pub optimized_value: i32,
     pub default_value: i32,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
+pub struct Access {
+    // Members may manage processes of other users, root always can
+    pub admin_group: String,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Self {
+            admin_group: "wheel".to_owned(),
+        }
+    }
+}
+
+// Missing keys keep their defaults, so older settings files still load
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Listener {
+    // Further clients are turned away until a slot frees up
+    pub max_connections: usize,
+    // Event subscribers are counted on their own, they don't hold a connection slot
+    pub max_subscribers: usize,
+    // Idle connections are closed after this many seconds without a packet
+    pub read_timeout_secs: u64,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Self {
+            max_connections: 32,
+            max_subscribers: 16,
+            read_timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Threads {
+    // Optimized processes are checked for new threads this often, 0 disables it
+    pub rescan_interval_secs: u64,
+}
+
+impl Default for Threads {
+    fn default() -> Self {
+        Self {
+            rescan_interval_secs: 2,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ProcessTree {
+    // Descendants of an optimized process get the same treatment
+    pub follow_children: bool,
+    pub scan_interval_secs: u64,
+}
+
+impl Default for ProcessTree {
+    fn default() -> Self {
+        Self {
+            follow_children: true,
+            scan_interval_secs: 1,
+        }
+    }
+}
+
+// Glob patterns a process is matched on, all that are set have to match
+#[derive(Deserialize, Clone)]
+pub struct MatchRule {
+    pub name: String,
+    pub exe: Option<String>,
+    pub comm: Option<String>,
+    pub cmdline: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Watcher {
+    // Optimize processes matching `rules` without being asked
+    pub enabled: bool,
+    pub rules: Vec<MatchRule>,
+    // Processes matching these are never picked up, even if a rule matches
+    pub exclude: Vec<MatchRule>,
+    // Used to look for new processes when process events are unavailable
+    pub scan_interval_secs: u64,
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rules: Vec::new(),
+            exclude: Vec::new(),
+            scan_interval_secs: 2,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Steam {
+    // Tell games from their Steam, Proton and Wine launchers and treat them differently
+    pub enabled: bool,
+}
+
+impl Default for Steam {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+// Overrides for some games, sections left out come from the top level
+#[derive(Deserialize, Clone)]
+pub struct Profile {
+    // Glob patterns on the executable's path or file name
+    #[serde(default)]
+    pub match_exe: Vec<String>,
+    pub cpu_affinity: Option<CpuAffinity>,
+    pub cpu_governor: Option<CpuGovernor>,
+    pub niceness: Option<Niceness>,
+    pub ioniceness: Option<IoNiceness>,
+}
+
+#[derive(Deserialize, Clone)]
 pub struct Settings {
     pub cpu_affinity: CpuAffinity,
     pub cpu_governor: CpuGovernor,
     pub niceness: Niceness,
     pub ioniceness: IoNiceness,
+    #[serde(default)]
+    pub access: Access,
+    #[serde(default)]
+    pub listener: Listener,
+    #[serde(default)]
+    pub threads: Threads,
+    #[serde(default)]
+    pub process_tree: ProcessTree,
+    #[serde(default)]
+    pub watcher: Watcher,
+    #[serde(default)]
+    pub steam: Steam,
+    // By name, `default` applies to processes no other profile is picked for
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
 }
 
 impl Default for Settings {
@@ -53,6 +195,13 @@ impl Default for Settings {
                 optimized_value: io::OPTIMIZED_IO_NICE_VALUE,
                 default_value: io::DEFAULT_IO_NICE_VALUE,
             },
+            access: Access::default(),
+            listener: Listener::default(),
+            threads: Threads::default(),
+            process_tree: ProcessTree::default(),
+            watcher: Watcher::default(),
+            steam: Steam::default(),
+            profiles: HashMap::new(),
         }
     }
 }
@@ -66,13 +215,106 @@ impl Settings {
         let s = cfg.try_deserialize::<Self>()?;
         Ok(s)
     }
+
+    // These settings with the knob sections the profile sets replaced
+    pub fn with_profile(&self, profile: &Profile) -> Settings {
+        let mut settings = self.clone();
+        settings.profiles.clear();
+        if let Some(section) = &profile.cpu_affinity {
+            settings.cpu_affinity = section.clone();
+        }
+        if let Some(section) = &profile.cpu_governor {
+            settings.cpu_governor = section.clone();
+        }
+        if let Some(section) = &profile.niceness {
+            settings.niceness = section.clone();
+        }
+        if let Some(section) = &profile.ioniceness {
+            settings.ioniceness = section.clone();
+        }
+        settings
+    }
 }
 
 pub fn get_cfg() -> anyhow::Result<Settings> {
     let mut config_path = std::env::home_dir().ok_or(anyhow::anyhow!("No home dir set"))?;
     config_path.push(".config/gaimode/settings.toml");
 
-    Ok(Settings::from_file(&config_path.to_str().ok_or(
-        anyhow::anyhow!("Could not convert path to str"),
-    )?)?)
+    Settings::from_file(
+        config_path
+            .to_str()
+            .ok_or(anyhow::anyhow!("Could not convert path to str"))?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Knob sections have no defaults, every settings file carries them
+    const KNOBS: &str = r#"
+[cpu_affinity]
+enabled = true
+[cpu_governor]
+enabled = true
+optimized_type = "performance"
+[niceness]
+enabled = true
+optimized_value = -10
+default_value = 0
+[ioniceness]
+enabled = true
+optimized_value = 1
+default_value = 4
+"#;
+
+    fn parse(sections: &str) -> Settings {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.toml");
+        std::fs::write(&path, format!("{}{}", KNOBS, sections)).unwrap();
+        Settings::from_file(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn watcher_section_without_interval() {
+        let settings = parse(
+            r#"
+[watcher]
+enabled = true
+[[watcher.rules]]
+name = "cs2"
+comm = "cs2"
+"#,
+        );
+        assert!(settings.watcher.enabled);
+        assert_eq!(settings.watcher.rules.len(), 1);
+        assert_eq!(
+            settings.watcher.scan_interval_secs,
+            Watcher::default().scan_interval_secs
+        );
+    }
+
+    #[test]
+    fn process_tree_section_without_interval() {
+        let settings = parse("[process_tree]\nfollow_children = false\n");
+        assert!(!settings.process_tree.follow_children);
+        assert_eq!(
+            settings.process_tree.scan_interval_secs,
+            ProcessTree::default().scan_interval_secs
+        );
+    }
+
+    #[test]
+    fn empty_threads_section() {
+        let settings = parse("[threads]\n# rescan_interval_secs = 0\n");
+        assert_eq!(
+            settings.threads.rescan_interval_secs,
+            Threads::default().rescan_interval_secs
+        );
+    }
+
+    #[test]
+    fn empty_steam_section() {
+        assert!(parse("[steam]\n").steam.enabled);
+    }
 }
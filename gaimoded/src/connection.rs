@@ -0,0 +1,238 @@
+use std::{
+    collections::VecDeque,
+    io::IoSliceMut,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use tokio::{io::AsyncWriteExt, io::Interest, net::UnixStream};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder},
+};
+
+// Clients attach at most one descriptor per packet
+const MAX_FDS_PER_PACKET: usize = 1;
+// The kernel ends a read after a message carrying descriptors, so besides the one of the
+// packet being read only the next packet's can be waiting
+const MAX_PENDING_FDS: usize = 2;
+const READ_CHUNK: usize = 4096;
+
+// Framed connection that also keeps file descriptors sent as SCM_RIGHTS.
+// Plain read() would make the kernel drop them, so every read goes through recvmsg.
+pub struct Connection {
+    stream: UnixStream,
+    codec: gaiproto::GaiprotoCodec,
+    read_buf: BytesMut,
+    received: u64, // Bytes read from the stream so far
+    decoded: u64,  // Bytes of them that made up whole packets
+    // Descriptors with the stream offset of the last byte that came along with them
+    pending_fds: VecDeque<(u64, OwnedFd)>,
+    packet_fd: Option<OwnedFd>, // Sent with the packet last returned by next()
+    failed: bool,               // The stream can't be resynchronized after an error
+}
+
+impl Connection {
+    pub fn new(stream: UnixStream) -> Connection {
+        Connection {
+            stream,
+            codec: gaiproto::GaiprotoCodec::new(),
+            read_buf: BytesMut::new(),
+            received: 0,
+            decoded: 0,
+            pending_fds: VecDeque::new(),
+            packet_fd: None,
+            failed: false,
+        }
+    }
+
+    // None once the peer hung up between packets, and after the first error like Framed
+    pub async fn next(&mut self) -> Option<Result<gaiproto::Gaiproto, gaiproto::CodecError>> {
+        // A descriptor nobody took is closed with its packet
+        self.packet_fd = None;
+        if self.failed {
+            return None;
+        }
+        let next = self.next_packet().await;
+        if let Some(Err(_)) = next {
+            self.failed = true;
+        }
+        next
+    }
+
+    async fn next_packet(&mut self) -> Option<Result<gaiproto::Gaiproto, gaiproto::CodecError>> {
+        loop {
+            let buffered = self.read_buf.len();
+            match self.codec.decode(&mut self.read_buf) {
+                Ok(Some(packet)) => {
+                    self.decoded += (buffered - self.read_buf.len()) as u64;
+                    return Some(self.attach_fds(packet));
+                }
+                Ok(None) => {}
+                Err(why) => return Some(Err(why)),
+            }
+
+            match self.read().await {
+                Ok(0) if self.read_buf.is_empty() => return None,
+                Ok(0) => {
+                    return Some(Err(gaiproto::CodecError::Io(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "connection closed in the middle of a packet",
+                    ))));
+                }
+                Ok(_) => {}
+                Err(why) => return Some(Err(why.into())),
+            }
+        }
+    }
+
+    // The descriptor sent with the packet last returned by next()
+    pub fn take_fd(&mut self) -> Option<OwnedFd> {
+        self.packet_fd.take()
+    }
+
+    // Moves the descriptors that came with the packet just decoded over to it
+    fn attach_fds(
+        &mut self,
+        packet: gaiproto::Gaiproto,
+    ) -> Result<gaiproto::Gaiproto, gaiproto::CodecError> {
+        let mut fds = Vec::new();
+        while let Some((offset, _)) = self.pending_fds.front()
+            && *offset < self.decoded
+        {
+            fds.extend(self.pending_fds.pop_front().map(|(_, fd)| fd));
+        }
+        if fds.len() > MAX_FDS_PER_PACKET {
+            return Err(invalid_data("more than one descriptor sent with a packet").into());
+        }
+        self.packet_fd = fds.pop();
+        Ok(packet)
+    }
+
+    pub async fn send(&mut self, packet: gaiproto::Gaiproto) -> Result<(), gaiproto::CodecError> {
+        let mut buf = BytesMut::new();
+        self.codec.encode(packet, &mut buf)?;
+        self.stream.write_all(&buf).await?;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> std::io::Result<usize> {
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            self.stream.readable().await?;
+            let fd = self.stream.as_raw_fd();
+            let received = self.stream.try_io(Interest::READABLE, || {
+                recv_with_fds(fd, &mut chunk).map_err(std::io::Error::from)
+            });
+            match received {
+                Ok((n, fds)) => {
+                    self.received += n as u64;
+                    // Data without descriptors that was queued before is read along, the read
+                    // stops after the message they came with, so the last byte is the sender's
+                    let offset = self.received.saturating_sub(1);
+                    self.read_buf.extend_from_slice(&chunk[..n]);
+                    self.pending_fds
+                        .extend(fds.into_iter().map(|fd| (offset, fd)));
+                    if self.pending_fds.len() > MAX_PENDING_FDS {
+                        return Err(invalid_data("too many descriptors sent"));
+                    }
+                    return Ok(n);
+                }
+                Err(why) if why.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(why) => return Err(why),
+            }
+        }
+    }
+}
+
+fn recv_with_fds(fd: std::os::fd::RawFd, buf: &mut [u8]) -> nix::Result<(usize, Vec<OwnedFd>)> {
+    use nix::sys::socket::{ControlMessageOwned, MsgFlags, UnixAddr, recvmsg};
+
+    let mut cmsg_buf = nix::cmsg_space!([std::os::fd::RawFd; MAX_FDS_PER_PACKET]);
+    let mut iov = [IoSliceMut::new(buf)];
+    let msg = recvmsg::<UnixAddr>(
+        fd,
+        &mut iov,
+        Some(&mut cmsg_buf),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+
+    let mut fds = Vec::new();
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(raw) = cmsg {
+            // The kernel installed these in our table, we own them now
+            fds.extend(
+                raw.into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    // The kernel closed the ones that did not fit
+    if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+        return Err(nix::Error::EPROTO);
+    }
+    Ok((msg.bytes, fds))
+}
+
+fn invalid_data(why: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, why)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn pair() -> (std::os::unix::net::UnixStream, Connection) {
+        let (client, daemon) = std::os::unix::net::UnixStream::pair().unwrap();
+        daemon.set_nonblocking(true).unwrap();
+        let daemon = Connection::new(UnixStream::from_std(daemon).unwrap());
+        (client, daemon)
+    }
+
+    #[tokio::test]
+    async fn descriptor_goes_to_the_packet_it_came_with() {
+        use nix::sys::socket::{ControlMessage, MsgFlags, sendmsg};
+
+        let (mut client, mut conn) = pair();
+        // Both are queued before the first read, which then takes them in one go
+        let plain = gaiproto::Message::ResetAll.encode().convert_to_bytes();
+        client.write_all(&plain).unwrap();
+        let with_fd = gaiproto::Message::OptimizeProcessFd {
+            pid: 7,
+            profile: None,
+        }
+        .encode()
+        .convert_to_bytes();
+        let fd = std::fs::File::open("/dev/null").unwrap();
+        let sent = sendmsg::<()>(
+            client.as_raw_fd(),
+            &[std::io::IoSlice::new(&with_fd)],
+            &[ControlMessage::ScmRights(&[fd.as_raw_fd()])],
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(sent, with_fd.len());
+
+        let first = conn.next().await.unwrap().unwrap();
+        assert_eq!(first.kind, gaiproto::K_RESET_ALL);
+        assert!(conn.take_fd().is_none());
+        let second = conn.next().await.unwrap().unwrap();
+        assert_eq!(second.kind, gaiproto::K_OPTIMIZE_PIDFD);
+        assert!(conn.take_fd().is_some());
+    }
+
+    #[tokio::test]
+    async fn ends_after_first_error() {
+        let (mut client, mut conn) = pair();
+        client.write_all(&[0; 6]).unwrap();
+
+        assert!(matches!(
+            conn.next().await,
+            Some(Err(gaiproto::CodecError::Decode(_)))
+        ));
+        // The peer is still connected, the stream is done for all that
+        assert!(conn.next().await.is_none());
+    }
+}
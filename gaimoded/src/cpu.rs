@@ -1,18 +1,17 @@
 use std::{
     io::{Read, Write},
     path::{Path, PathBuf},
-    time::Duration,
 };
 
-pub const SCALING_AV_GOV_POLICY_PATH_BLOB: &'static str =
-    "/sys/devices/system/cpu/cpufreq/policy*/scaling_available_governors";
-pub const SCALING_GOV_POLICY_PATH_GLOB: &'static str =
-    "/sys/devices/system/cpu/cpufreq/policy*/scaling_governor";
-pub const PERF_GOV: &'static str = "performance";
+use crate::{paths::SystemPaths, procstat};
 
-pub fn is_gov_available(gov: &str) -> anyhow::Result<bool> {
+pub const SCALING_AV_GOV_FILE: &str = "scaling_available_governors";
+pub const SCALING_GOV_FILE: &str = "scaling_governor";
+pub const PERF_GOV: &str = "performance";
+
+pub fn is_gov_available(paths: &SystemPaths, gov: &str) -> anyhow::Result<bool> {
     // NOTE: 1. cpu*/cpufreq is symlink to ../cpufreq/policy*
-    for entry in glob::glob(SCALING_AV_GOV_POLICY_PATH_BLOB)? {
+    for entry in glob::glob(&paths.cpufreq_glob(SCALING_AV_GOV_FILE))? {
         let mut file = std::fs::File::open(entry?)?;
         let mut buf = String::new();
         file.read_to_string(&mut buf)?;
@@ -24,24 +23,19 @@ pub fn is_gov_available(gov: &str) -> anyhow::Result<bool> {
     Ok(false)
 }
 
-pub fn set_gov_all(gov: &str) -> anyhow::Result<()> {
-    // Since one policy can be used by several cores, it's faster to iterate policies
-    for entry in glob::glob(SCALING_GOV_POLICY_PATH_GLOB)? {
-        let mut file = std::fs::OpenOptions::new().write(true).open(entry?)?;
-        file.write(gov.as_bytes())?;
-    }
-    Ok(())
-}
-
 pub fn set_gov(path: &Path, gov: &str) -> anyhow::Result<()> {
-    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
-    file.write(gov.as_bytes())?;
+    // sysfs ignores O_TRUNC, a plain file (tests) would keep the tail of a longer name
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(gov.as_bytes())?;
     Ok(())
 }
 
-pub fn get_govs() -> anyhow::Result<Vec<(PathBuf, String)>> {
+pub fn get_govs(paths: &SystemPaths) -> anyhow::Result<Vec<(PathBuf, String)>> {
     let mut res = Vec::new();
-    for entry in glob::glob(SCALING_GOV_POLICY_PATH_GLOB)? {
+    for entry in glob::glob(&paths.cpufreq_glob(SCALING_GOV_FILE))? {
         let path = entry?;
         let mut file = std::fs::File::open(&path)?;
         let mut buf = String::new();
@@ -62,67 +56,15 @@ pub fn cpus_num() -> anyhow::Result<i64> {
     }
 }
 
-// Returns a CPU load percentage (index is cpu number)
-pub fn cpus_load() -> anyhow::Result<Vec<(usize, f32)>> {
-    let mut res = Vec::new();
-    let cpus_n = cpus_num()?;
-
-    let mut cpu_stat_start = String::new();
-    let mut cpu_stat_end = String::new();
-
-    {
-        let mut file = std::fs::File::open("/proc/stat")?;
-        file.read_to_string(&mut cpu_stat_start)?;
-    }
-    std::thread::sleep(Duration::from_secs(1));
-    {
-        let mut file = std::fs::File::open("/proc/stat")?;
-        file.read_to_string(&mut cpu_stat_end)?;
-    }
-
-    let cpu_values_start = &cpu_stat_start
-        .split('\n')
-        .map(|line| line.split(' ').collect::<Vec<&str>>())
-        .collect::<Vec<Vec<&str>>>()[1..cpus_n as usize];
-    let cpu_values_end = &cpu_stat_end
-        .split('\n')
-        .map(|line| line.split(' ').collect::<Vec<&str>>())
-        .collect::<Vec<Vec<&str>>>()[1..cpus_n as usize];
-
-    for (idx, (values_start, values_end)) in cpu_values_start
+// Returns a CPU load percentage between two /proc/stat snapshots, keyed by CPU number
+pub fn cpus_load(cpu_stat_start: &str, cpu_stat_end: &str) -> anyhow::Result<Vec<(usize, f32)>> {
+    let start = procstat::parse(cpu_stat_start)?;
+    let end = procstat::parse(cpu_stat_end)?;
+    // A CPU that went offline or came online in between has nothing to compare
+    Ok(end
         .iter()
-        .zip(cpu_values_end.iter())
-        .enumerate()
-    {
-        if values_start.is_empty() {
-            continue;
-        }
-
-        // Parse deltas
-        let user_d = values_end[1].parse::<u32>()? - values_start[1].parse::<u32>()?;
-        let nice_d = values_end[2].parse::<u32>()? - values_start[2].parse::<u32>()?;
-        let system_d = values_end[3].parse::<u32>()? - values_start[3].parse::<u32>()?;
-        let idle_d = values_end[4].parse::<u32>()? - values_start[4].parse::<u32>()?;
-        let iowait_d = values_end[5].parse::<u32>()? - values_start[5].parse::<u32>()?;
-        let irq_d = values_end[6].parse::<u32>()? - values_start[6].parse::<u32>()?;
-        let softirq_d = values_end[7].parse::<u32>()? - values_start[7].parse::<u32>()?;
-
-        // Total delta = sum of all deltas
-        let total_d = user_d + nice_d + system_d + idle_d + iowait_d + irq_d + softirq_d;
-
-        // Idle time
-        let idle_total_d = idle_d + iowait_d;
-
-        // CPU load percentage
-        let load = if total_d == 0 {
-            0.0
-        } else {
-            ((total_d - idle_total_d) as f32 / total_d as f32) * 100.0f32
-        };
-
-        res.push((idx, load));
-    }
-    Ok(res)
+        .filter_map(|(cpu, times)| Some((*cpu, times.load_since(start.get(cpu)?))))
+        .collect())
 }
 
 pub fn get_aff_mask(pid: nix::unistd::Pid) -> anyhow::Result<libc::cpu_set_t> {
@@ -140,6 +82,13 @@ pub fn get_aff_mask(pid: nix::unistd::Pid) -> anyhow::Result<libc::cpu_set_t> {
     }
 }
 
+// CPUs that are set in the mask
+pub fn mask_cpus(mask: &libc::cpu_set_t) -> Vec<usize> {
+    (0..libc::CPU_SETSIZE as usize)
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, mask) })
+        .collect()
+}
+
 pub fn set_aff_mask(pid: nix::unistd::Pid, mask: libc::cpu_set_t) -> anyhow::Result<()> {
     unsafe {
         let ret = libc::sched_setaffinity(
@@ -171,6 +120,11 @@ pub fn pin_process(pid: nix::unistd::Pid, cpu: usize) -> anyhow::Result<()> {
 }
 
 pub fn pin_process_excluding(pid: nix::unistd::Pid, cpu_exclude: usize) -> anyhow::Result<()> {
+    exclude_cpus(pid, &[cpu_exclude])
+}
+
+// Lets the thread run anywhere but on `cpus_exclude`
+pub fn exclude_cpus(pid: nix::unistd::Pid, cpus_exclude: &[usize]) -> anyhow::Result<()> {
     unsafe {
         let mut set: libc::cpu_set_t = std::mem::zeroed();
 
@@ -178,9 +132,10 @@ pub fn pin_process_excluding(pid: nix::unistd::Pid, cpu_exclude: usize) -> anyho
         for i in 0..cpus_n {
             libc::CPU_SET(i as usize, &mut set);
         }
-        libc::CPU_CLR(cpu_exclude, &mut set);
+        for cpu in cpus_exclude {
+            libc::CPU_CLR(*cpu, &mut set);
+        }
 
-        // libc::CPU_SET(cpu, &mut set);
         let ret = libc::sched_setaffinity(
             pid.as_raw(),
             std::mem::size_of::<libc::cpu_set_t>(),
@@ -193,9 +148,8 @@ pub fn pin_process_excluding(pid: nix::unistd::Pid, cpu_exclude: usize) -> anyho
     Ok(())
 }
 
-pub fn cpu_core_id(cpu: usize) -> anyhow::Result<usize> {
-    let path = format!("/sys/devices/system/cpu/cpu{}/topology/core_id", cpu);
-    let mut file = std::fs::File::open(&path)?;
+pub fn cpu_core_id(paths: &SystemPaths, cpu: usize) -> anyhow::Result<usize> {
+    let mut file = std::fs::File::open(paths.core_id(cpu))?;
     let mut str = String::new();
     file.read_to_string(&mut str)?;
     let num = str.trim().parse::<usize>()?;
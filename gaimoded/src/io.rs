@@ -6,7 +6,7 @@ pub const OPTIMIZED_IO_NICE_VALUE: i32 = 1;
 pub const DEFAULT_IO_NICE_VALUE: i32 = 4;
 
 #[inline]
-fn ioprio_prio_data(ioprio: i32) -> i32 {
+pub fn ioprio_prio_data(ioprio: i32) -> i32 {
     (ioprio) & IOPRIO_PRIO_MASK
 }
 
@@ -16,38 +16,38 @@ fn ioprio_value(prioclass: i32, priolevel: i32) -> u16 {
 }
 
 pub fn process_io_niceness(pid: nix::unistd::Pid) -> anyhow::Result<i32> {
+    Ok(ioprio_prio_data(thread_ioprio(pid)?))
+}
+
+// Raw ioprio (class and level) of a single thread
+pub fn thread_ioprio(tid: nix::unistd::Pid) -> anyhow::Result<i32> {
     unsafe {
         *libc::__errno_location() = 0;
-        let ret = libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, pid.as_raw());
+        let ret = libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, tid.as_raw());
         if ret == -1 && *libc::__errno_location() != 0 {
-            return Err(anyhow::anyhow!("Failed to get process IO niceness"));
+            return Err(anyhow::anyhow!("Failed to get thread {} IO priority", tid));
         }
-
-        Ok(ioprio_prio_data(ret as i32))
+        Ok(ret as i32)
     }
 }
 
-// Optimizes IO performance when game has to load assets
-pub fn set_process_io_niceness(pid: nix::unistd::Pid, ioniceness: i32) -> anyhow::Result<()> {
-    unsafe {
-        let tasks_path = format!("/proc/{}/task/", pid.as_raw());
-        let dir_iter = std::fs::read_dir(&tasks_path)?;
-        for task in dir_iter {
-            let task_tid = task?.file_name().to_string_lossy().parse::<u32>()?;
-
-            let value = ioprio_value(IOPRIO_CLASS_BE, ioniceness);
-            let ret = libc::syscall(
-                libc::SYS_ioprio_set,
-                IOPRIO_WHO_PROCESS,
-                task_tid,
-                value as i32,
-            );
-            if ret < 0 {
-                // Should not fail if failed to change single process' niceness
-                tracing::error!("Failed to change TID I/O Niceness");
-            }
-        }
-
-        Ok(())
+// Puts back a value read with `thread_ioprio`, class included
+pub fn set_thread_ioprio(tid: nix::unistd::Pid, ioprio: i32) -> anyhow::Result<()> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            tid.as_raw(),
+            ioprio,
+        )
+    };
+    if ret < 0 {
+        return Err(anyhow::anyhow!("Failed to set thread {} IO priority", tid));
     }
+    Ok(())
+}
+
+// Best effort class at the given level, what optimizing and resetting without a saved value use
+pub fn set_thread_io_niceness(tid: nix::unistd::Pid, ioniceness: i32) -> anyhow::Result<()> {
+    set_thread_ioprio(tid, ioprio_value(IOPRIO_CLASS_BE, ioniceness) as i32)
 }
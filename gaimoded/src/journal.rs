@@ -0,0 +1,206 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+const JOURNAL_FILE: &str = "journal";
+
+// Original values of system files the daemon changed, kept on disk so a crash can't
+// leave them changed for good. Every value is recorded before its file is written.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    pub fn open(dir: &Path) -> anyhow::Result<Journal> {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let path = dir.join(JOURNAL_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .mode(0o600)
+            .open(&path)?;
+        Ok(Journal { path, file })
+    }
+
+    // `path` held `original` before it gets changed, on disk when this returns
+    pub fn record(&mut self, path: &Path, original: &str) -> anyhow::Result<()> {
+        let path = path
+            .to_str()
+            .filter(|path| !path.contains(['\t', '\n']))
+            .ok_or_else(|| anyhow::anyhow!("Can't journal path {}", path.display()))?;
+        let original = original.trim();
+        if original.contains(['\t', '\n']) {
+            return Err(anyhow::anyhow!("Can't journal value of {}", path));
+        }
+        self.file
+            .write_all(format!("{}\t{}\n", path, original).as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // What `path` held before a previous run changed it, if that run could not put it back
+    pub fn original(&self, path: &Path) -> anyhow::Result<Option<String>> {
+        let entries = parse(&std::fs::read_to_string(&self.path)?);
+        Ok(entries
+            .into_iter()
+            .find(|(known, _)| known == path)
+            .map(|(_, original)| original))
+    }
+
+    // `restored` were put back, entries for other paths stay
+    pub fn forget(&mut self, restored: &[PathBuf]) -> anyhow::Result<()> {
+        let entries = parse(&std::fs::read_to_string(&self.path)?);
+        self.clear()?;
+        for (path, original) in entries {
+            if !restored.contains(&path) {
+                self.record(&path, &original)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Everything recorded has been put back
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Writes back what a previous run left changed, returns how many files were restored
+    pub fn replay(&mut self) -> anyhow::Result<usize> {
+        let entries = parse(&std::fs::read_to_string(&self.path)?);
+        let total = entries.len();
+        let mut failed = Vec::new();
+        for (path, original) in entries {
+            if let Err(why) = std::fs::write(&path, &original) {
+                tracing::error!("Could not restore {}: {}", path.display(), why);
+                failed.push((path, original));
+            }
+        }
+        // Failed entries stay for the next start, the file may be writable by then
+        self.clear()?;
+        for (path, original) in &failed {
+            self.record(path, original)?;
+        }
+        Ok(total - failed.len())
+    }
+}
+
+// The first value recorded for each path is the original one, later ones were already ours.
+// A line cut short by a crash was never followed by a write, so it is skipped.
+fn parse(journal: &str) -> Vec<(PathBuf, String)> {
+    let mut entries: Vec<(PathBuf, String)> = Vec::new();
+    let complete = journal.rsplit_once('\n').map_or("", |(lines, _)| lines);
+    for line in complete.lines() {
+        let Some((path, original)) = line.split_once('\t') else {
+            continue;
+        };
+        let path = PathBuf::from(path);
+        if entries.iter().all(|(known, _)| *known != path) {
+            entries.push((path, original.to_owned()));
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_value_per_path_wins() {
+        let journal =
+            "/sys/policy0\tschedutil\n/sys/policy1\tpowersave\n/sys/policy0\tperformance\n";
+        assert_eq!(
+            parse(journal),
+            vec![
+                (PathBuf::from("/sys/policy0"), "schedutil".to_owned()),
+                (PathBuf::from("/sys/policy1"), "powersave".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_cut_short_and_malformed_lines() {
+        let journal = "garbage\n/sys/policy0\tschedutil\n/sys/policy1\tpow";
+        assert_eq!(
+            parse(journal),
+            vec![(PathBuf::from("/sys/policy0"), "schedutil".to_owned())]
+        );
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn replay_restores_and_clears() {
+        let dir = tempfile::tempdir().unwrap();
+        let knob = dir.path().join("scaling_governor");
+        std::fs::write(&knob, "performance\n").unwrap();
+
+        let mut journal = Journal::open(&dir.path().join("state")).unwrap();
+        journal.record(&knob, "schedutil\n").unwrap();
+        drop(journal);
+
+        let mut journal = Journal::open(&dir.path().join("state")).unwrap();
+        assert_eq!(journal.replay().unwrap(), 1);
+        assert_eq!(std::fs::read_to_string(&knob).unwrap(), "schedutil");
+        assert_eq!(journal.replay().unwrap(), 0);
+    }
+
+    #[test]
+    fn replay_keeps_entries_it_could_not_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let knob = dir.path().join("scaling_governor");
+        std::fs::write(&knob, "performance\n").unwrap();
+        // Writing to a directory fails even for root
+        let stuck = dir.path().join("stuck");
+        std::fs::create_dir(&stuck).unwrap();
+
+        let state = dir.path().join("state");
+        let mut journal = Journal::open(&state).unwrap();
+        journal.record(&knob, "schedutil").unwrap();
+        journal.record(&stuck, "powersave").unwrap();
+
+        assert_eq!(journal.replay().unwrap(), 1);
+        assert_eq!(std::fs::read_to_string(&knob).unwrap(), "schedutil");
+        let left = std::fs::read_to_string(state.join(JOURNAL_FILE)).unwrap();
+        assert_eq!(parse(&left), vec![(stuck.clone(), "powersave".to_owned())]);
+
+        std::fs::remove_dir(&stuck).unwrap();
+        assert_eq!(journal.replay().unwrap(), 1);
+        assert_eq!(std::fs::read_to_string(&stuck).unwrap(), "powersave");
+        assert!(
+            std::fs::read_to_string(state.join(JOURNAL_FILE))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn forget_keeps_other_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = Journal::open(dir.path()).unwrap();
+        let (policy0, policy1) = (PathBuf::from("/sys/policy0"), PathBuf::from("/sys/policy1"));
+        journal.record(&policy0, "schedutil").unwrap();
+        journal.record(&policy1, "powersave").unwrap();
+        journal.record(&policy0, "performance").unwrap();
+
+        assert_eq!(
+            journal.original(&policy0).unwrap().as_deref(),
+            Some("schedutil")
+        );
+        journal.forget(std::slice::from_ref(&policy0)).unwrap();
+        assert_eq!(journal.original(&policy0).unwrap(), None);
+        assert_eq!(
+            journal.original(&policy1).unwrap().as_deref(),
+            Some("powersave")
+        );
+    }
+}
@@ -0,0 +1,20 @@
+pub mod auth;
+pub mod cfg;
+pub mod connection;
+pub mod cpu;
+pub mod io;
+pub mod journal;
+pub mod listener;
+pub mod load;
+pub mod optimizer;
+pub mod paths;
+pub mod pidfd;
+pub mod proc_events;
+pub mod procstat;
+pub mod profile;
+pub mod scheduler;
+pub mod socket;
+pub mod steam;
+pub mod transaction;
+pub mod utils;
+pub mod watcher;
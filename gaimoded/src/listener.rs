@@ -1,53 +1,433 @@
-use tokio::{io::AsyncReadExt, sync::mpsc::UnboundedSender};
+use std::{os::fd::OwnedFd, sync::Arc, time::Duration};
 
-use crate::utils;
+use tokio::{
+    net::UnixStream,
+    sync::{OwnedSemaphorePermit, Semaphore, broadcast, mpsc::UnboundedSender, oneshot},
+};
+
+use crate::{
+    auth::Peer,
+    cfg,
+    connection::Connection,
+    paths::SystemPaths,
+    utils,
+    watcher::{self, ProcessInfo},
+};
 
 pub struct UdsListener {
     pub listener: tokio::net::UnixListener,
+    clients: Arc<ClientContext>,
+    slots: Arc<Semaphore>, // One permit per connection being served
+}
+
+// What every connection task needs, shared between them
+struct ClientContext {
+    capabilities: u32, // Advertised to clients in HELLO
+    events: broadcast::Sender<gaiproto::Event>,
+    admin_group: String,
+    read_timeout: Duration,
+    paths: SystemPaths, // Process ownership is looked up under its procfs root
+    watch_rules: Arc<watcher::Rules>,
+    subscribers: Arc<Semaphore>, // One permit per connection streaming events
 }
 
 impl UdsListener {
-    pub fn new(listener: tokio::net::UnixListener) -> UdsListener {
-        UdsListener { listener }
+    pub fn new(
+        listener: tokio::net::UnixListener,
+        capabilities: u32,
+        events: broadcast::Sender<gaiproto::Event>,
+        access: cfg::Access,
+        limits: cfg::Listener,
+        paths: SystemPaths,
+        watch_rules: Arc<watcher::Rules>,
+    ) -> UdsListener {
+        UdsListener {
+            listener,
+            clients: Arc::new(ClientContext {
+                capabilities,
+                events,
+                admin_group: access.admin_group,
+                read_timeout: Duration::from_secs(limits.read_timeout_secs),
+                paths,
+                watch_rules,
+                subscribers: Arc::new(Semaphore::new(limits.max_subscribers)),
+            }),
+            slots: Arc::new(Semaphore::new(limits.max_connections)),
+        }
     }
     pub async fn process(&mut self, tx: UnboundedSender<utils::Commands>) -> anyhow::Result<()> {
-        match self.listener.accept().await {
-            Ok((mut stream, _addr)) => {
-                let mut buf: [u8; 2048] = [0u8; 2048];
-                stream.read(&mut buf).await?;
-
-                let packet = gaiproto::Gaiproto::from_bytes(buf.to_vec());
-                handle_packet(&packet, tx.clone()).await?;
-            }
+        let stream = match self.listener.accept().await {
+            Ok((stream, _addr)) => stream,
             Err(why) => {
                 return Err(anyhow::anyhow!("Accept failed: {}", why));
             }
+        };
+
+        let Ok(permit) = self.slots.clone().try_acquire_owned() else {
+            tracing::warn!("Too many clients connected, refusing a new one");
+            let busy = busy("Too many clients are connected");
+            let packet = gaiproto::Message::Response(busy)
+                .encode()
+                .convert_to_bytes();
+            // A fresh socket's buffer takes it whole, the accept loop must not wait on a client.
+            // tokio has not seen it writable yet, so the write goes around it.
+            if let Ok(mut stream) = stream.into_std() {
+                let _ = std::io::Write::write(&mut stream, &packet);
+            }
+            // Dropping the stream hangs up on the client
+            return Ok(());
+        };
+        let clients = self.clients.clone();
+        tokio::spawn(async move {
+            if let Err(why) = clients.handle_connection(stream, tx, permit).await {
+                tracing::warn!("Connection closed: {}", why);
+            }
+        });
+        Ok(())
+    }
+}
+
+impl ClientContext {
+    // Serves packets on one connection until the client hangs up or goes quiet.
+    // `slot` is held for as long as the connection takes requests.
+    async fn handle_connection(
+        &self,
+        stream: UnixStream,
+        tx: UnboundedSender<utils::Commands>,
+        slot: OwnedSemaphorePermit,
+    ) -> anyhow::Result<()> {
+        let peer = Peer::from_stream(&stream, &self.admin_group)?;
+        tracing::debug!(
+            "Client connected: uid {}, pid {:?}, admin {}",
+            peer.uid,
+            peer.pid,
+            peer.is_admin
+        );
+        let mut conn = Connection::new(stream);
+        // Clients that skip HELLO are taken to speak the current version
+        let mut version = gaiproto::PROTOCOL_VERSION;
+        loop {
+            let frame = match tokio::time::timeout(self.read_timeout, conn.next()).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(_) => {
+                    tracing::debug!("Client uid {} timed out", peer.uid);
+                    break;
+                }
+            };
+            let packet = match frame {
+                Ok(packet) => packet,
+                Err(gaiproto::CodecError::Decode(why)) => {
+                    // Stream can't be resynchronized after a bad frame
+                    conn.send(invalid_request(why).encode()).await?;
+                    break;
+                }
+                Err(why) => return Err(why.into()),
+            };
+
+            let message = match gaiproto::Message::decode_for(&packet, version) {
+                Ok(message) => message,
+                Err(why) => {
+                    conn.send(invalid_request(why).encode()).await?;
+                    continue;
+                }
+            };
+
+            if let gaiproto::Message::Hello(hello) = message {
+                let (reply, compatible) = self.handle_hello(hello);
+                conn.send(reply.encode()).await?;
+                if !compatible {
+                    break;
+                }
+                version = hello.negotiated();
+                continue;
+            }
+
+            if let gaiproto::Message::Subscribe = message {
+                let Ok(subscription) = self.subscribers.clone().try_acquire_owned() else {
+                    tracing::warn!("Too many subscribers, refusing client uid {}", peer.uid);
+                    let busy = busy("Too many clients are subscribed to events");
+                    conn.send(gaiproto::Message::Response(busy).encode())
+                        .await?;
+                    continue;
+                };
+                // Subscribers stay connected for long, they don't keep others from connecting
+                drop(slot);
+                let events = self.events.subscribe();
+                let ok = gaiproto::Message::Response(gaiproto::Response::Ok(Vec::new()));
+                conn.send(ok.encode()).await?;
+                // Subscribers are expected to stay quiet, so no read timeout here
+                let streamed = stream_events(conn, events).await;
+                drop(subscription);
+                return streamed;
+            }
+
+            if let gaiproto::Message::WatchRulesTest { pid } = message {
+                let reply = self.watch_rules_test(pid, &peer).await;
+                conn.send(reply.encode()).await?;
+                continue;
+            }
+
+            let pidfd = match message {
+                gaiproto::Message::OptimizeProcessFd { .. } => match conn.take_fd() {
+                    Some(fd) => Some(fd),
+                    None => {
+                        let why = invalid_request("pidfd missing from optimize request");
+                        conn.send(why.encode()).await?;
+                        continue;
+                    }
+                },
+                _ if conn.take_fd().is_some() => {
+                    let why = invalid_request("descriptor sent with a request that takes none");
+                    conn.send(why.encode()).await?;
+                    continue;
+                }
+                _ => None,
+            };
+            let response = handle_message(message, pidfd, &peer, &self.paths, tx.clone()).await?;
+            conn.send(fit_reply(response, version)).await?;
         }
         Ok(())
     }
+
+    async fn watch_rules_test(&self, pid: i32, peer: &Peer) -> gaiproto::Message {
+        let pid = nix::unistd::Pid::from_raw(pid);
+        if !peer.may_manage(&self.paths, pid) {
+            return gaiproto::Message::Response(peer.denied(pid.as_raw()));
+        }
+        let paths = self.paths.clone();
+        let info = tokio::task::spawn_blocking(move || ProcessInfo::read(&paths, pid)).await;
+        let info = match info {
+            Ok(Ok(info)) => info,
+            Ok(Err(why)) => {
+                return gaiproto::Message::Response(gaiproto::Response::Error {
+                    code: gaiproto::E_NOT_FOUND,
+                    message: format!("Could not read process {}: {}", pid, why),
+                });
+            }
+            Err(why) => {
+                return gaiproto::Message::Response(gaiproto::Response::Error {
+                    code: gaiproto::E_INTERNAL,
+                    message: why.to_string(),
+                });
+            }
+        };
+        gaiproto::Message::WatchReport(gaiproto::WatchReport {
+            pid: pid.as_raw(),
+            verdict: self.watch_rules.evaluate(&info),
+            exe: info.exe,
+            comm: info.comm,
+            cmdline: info.cmdline,
+            watcher_enabled: self.watch_rules.enabled,
+        })
+    }
+
+    // Returns the reply and whether the conversation may go on
+    fn handle_hello(&self, hello: gaiproto::Hello) -> (gaiproto::Message, bool) {
+        if hello.is_compatible() {
+            let reply = gaiproto::Hello::new(self.capabilities);
+            return (gaiproto::Message::Hello(reply), true);
+        }
+
+        tracing::warn!("Refused client with protocol version {}", hello.version);
+        let response = gaiproto::Response::Error {
+            code: gaiproto::E_UNSUPPORTED_VERSION,
+            message: format!(
+                "Protocol version {} is not supported, daemon speaks {} (oldest supported {})",
+                hello.version,
+                gaiproto::PROTOCOL_VERSION,
+                gaiproto::MIN_SUPPORTED_VERSION
+            ),
+        };
+        (gaiproto::Message::Response(response), false)
+    }
 }
 
-async fn handle_packet(
-    pkt: &gaiproto::Gaiproto,
-    tx: UnboundedSender<utils::Commands>,
+// Pushes events to a subscribed client until it disconnects
+async fn stream_events(
+    mut conn: Connection,
+    mut events: broadcast::Receiver<gaiproto::Event>,
 ) -> anyhow::Result<()> {
-    match pkt.kind {
-        gaiproto::K_OPTIMIZE_PROCESS => {
-            let pid_raw = i32::from_be_bytes(pkt.payload.clone().try_into().unwrap());
-            let pid = nix::unistd::Pid::from_raw(pid_raw);
-            tx.send(utils::Commands::OptimizeProcess(pid))?;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => conn.send(gaiproto::Message::Event(event).encode()).await?,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Subscriber fell behind, dropped {} events", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Subscribers don't send anything, this only notices the hang up
+            frame = conn.next() => if let None | Some(Err(_)) = frame {
+                break;
+            },
+        }
+    }
+    Ok(())
+}
+
+// Status and batch replies grow with what is tracked, one that can't be framed becomes an error
+fn fit_reply(reply: gaiproto::Message, version: u16) -> gaiproto::Gaiproto {
+    let packet = reply.encode_for(version);
+    if packet.size as usize <= gaiproto::MAX_PACKET_SIZE {
+        return packet;
+    }
+    tracing::warn!(
+        "Reply of {} bytes is over the {} byte limit",
+        packet.size,
+        gaiproto::MAX_PACKET_SIZE
+    );
+    let too_large = gaiproto::Response::Error {
+        code: gaiproto::E_INTERNAL,
+        message: format!(
+            "Reply is too large to send ({} bytes, limit {})",
+            packet.size,
+            gaiproto::MAX_PACKET_SIZE
+        ),
+    };
+    gaiproto::Message::Response(too_large).encode()
+}
+
+fn busy(why: &str) -> gaiproto::Response {
+    gaiproto::Response::Error {
+        code: gaiproto::E_BUSY,
+        message: why.to_owned(),
+    }
+}
+
+fn invalid_request(why: impl std::fmt::Display) -> gaiproto::Response {
+    tracing::warn!("Rejected malformed packet: {}", why);
+    gaiproto::Response::Error {
+        code: gaiproto::E_INVALID_REQUEST,
+        message: why.to_string(),
+    }
+}
+
+// Forwards the request to the optimizer and waits until it reports the outcome
+async fn handle_message(
+    message: gaiproto::Message,
+    pidfd: Option<OwnedFd>,
+    peer: &Peer,
+    paths: &SystemPaths,
+    tx: UnboundedSender<utils::Commands>,
+) -> anyhow::Result<gaiproto::Message> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    match message {
+        gaiproto::Message::OptimizeProcess { pid, profile }
+        | gaiproto::Message::OptimizeProcessFd { pid, profile } => {
+            let pid = nix::unistd::Pid::from_raw(pid);
+            if !peer.may_manage(paths, pid) {
+                return Ok(gaiproto::Message::Response(peer.denied(pid.as_raw())));
+            }
+            tx.send(utils::Commands::OptimizeProcess(
+                pid, pidfd, profile, reply_tx,
+            ))?;
+        }
+        gaiproto::Message::ResetProcess { pid } => {
+            let pid = nix::unistd::Pid::from_raw(pid);
+            if !peer.may_manage(paths, pid) {
+                return Ok(gaiproto::Message::Response(peer.denied(pid.as_raw())));
+            }
+            tx.send(utils::Commands::ResetProcess(pid, reply_tx))?;
+        }
+        gaiproto::Message::ResetAll => {
+            tx.send(utils::Commands::ResetAll(peer.scope(), reply_tx))?;
         }
-        gaiproto::K_RESET_PROCESS => {
-            let pid_raw = i32::from_be_bytes(pkt.payload.clone().try_into().unwrap());
-            let pid = nix::unistd::Pid::from_raw(pid_raw);
-            tx.send(utils::Commands::ResetProcess(pid))?;
+        gaiproto::Message::OptimizeBatch { pids } => {
+            let results =
+                handle_batch(pids, peer, paths, utils::Commands::OptimizeBatch, tx).await?;
+            return Ok(gaiproto::Message::BatchResult(results));
         }
-        gaiproto::K_RESET_ALL => {
-            tx.send(utils::Commands::ResetAll)?;
+        gaiproto::Message::ResetBatch { pids } => {
+            let results = handle_batch(pids, peer, paths, utils::Commands::ResetBatch, tx).await?;
+            return Ok(gaiproto::Message::BatchResult(results));
         }
-        _ => {
-            // Ignore
+        gaiproto::Message::StatusRequest => {
+            let (status_tx, status_rx) = oneshot::channel();
+            tx.send(utils::Commands::Status(status_tx))?;
+            return Ok(gaiproto::Message::Status(status_rx.await?));
+        }
+        other => {
+            return Ok(gaiproto::Message::Response(invalid_request(format!(
+                "Unexpected message {:?}",
+                other
+            ))));
         }
     }
-    Ok(())
+    Ok(gaiproto::Message::Response(reply_rx.await?))
+}
+
+// Sends only the PIDs the peer may manage, the rest are answered with a denial in place
+async fn handle_batch(
+    pids: Vec<i32>,
+    peer: &Peer,
+    paths: &SystemPaths,
+    command: fn(Vec<nix::unistd::Pid>, utils::BatchReply) -> utils::Commands,
+    tx: UnboundedSender<utils::Commands>,
+) -> anyhow::Result<Vec<gaiproto::PidResult>> {
+    let permitted = pids
+        .iter()
+        .map(|pid| peer.may_manage(paths, nix::unistd::Pid::from_raw(*pid)))
+        .collect::<Vec<_>>();
+    let allowed = pids
+        .iter()
+        .zip(&permitted)
+        .filter(|(_, ok)| **ok)
+        .map(|(pid, _)| nix::unistd::Pid::from_raw(*pid))
+        .collect::<Vec<_>>();
+
+    let mut done = Vec::new().into_iter();
+    if !allowed.is_empty() {
+        let (batch_tx, batch_rx) = oneshot::channel();
+        tx.send(command(allowed, batch_tx))?;
+        done = batch_rx.await?.into_iter();
+    }
+
+    let mut results = Vec::with_capacity(pids.len());
+    for (pid, ok) in pids.into_iter().zip(permitted) {
+        if ok {
+            results.extend(done.next());
+        } else {
+            results.push(gaiproto::PidResult {
+                pid,
+                response: peer.denied(pid),
+            });
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(processes: usize) -> gaiproto::Message {
+        let process = |pid| gaiproto::ProcessStatus {
+            pid,
+            niceness: Some(-10),
+            ioniceness: Some(0),
+            affinity: Some((0..64).collect()),
+            pinned_cpu: None,
+            profile: None,
+        };
+        gaiproto::Message::Status(gaiproto::StatusReport {
+            is_optimized: true,
+            governors: Vec::new(),
+            processes: (0..processes as i32).map(process).collect(),
+        })
+    }
+
+    #[test]
+    fn oversized_reply_becomes_error() {
+        let small = fit_reply(status(1), gaiproto::PROTOCOL_VERSION);
+        assert_eq!(small.kind, gaiproto::K_STATUS_REPORT);
+
+        let large = fit_reply(status(1000), gaiproto::PROTOCOL_VERSION);
+        let gaiproto::Message::Response(gaiproto::Response::Error { code, .. }) =
+            gaiproto::Message::decode(&large).unwrap()
+        else {
+            panic!("oversized status was sent as is");
+        };
+        assert_eq!(code, gaiproto::E_INTERNAL);
+    }
 }
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::{cpu, paths::SystemPaths};
+
+// Loads are averaged over this window
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Keeps a rolling per-CPU load figure so picking a CPU never has to wait for a sample
+pub struct LoadSampler {
+    loads: watch::Receiver<Vec<(usize, f32)>>,
+}
+
+impl LoadSampler {
+    // Sampling stops once the sampler is dropped
+    pub fn spawn(paths: &SystemPaths) -> LoadSampler {
+        let (tx, loads) = watch::channel(Vec::new());
+        let stat = paths.stat();
+        tokio::spawn(async move {
+            let mut previous: Option<String> = None;
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let current = match tokio::fs::read_to_string(&stat).await {
+                    Ok(stat) => stat,
+                    Err(why) => {
+                        tracing::error!("Could not read {}: {}", stat.display(), why);
+                        continue;
+                    }
+                };
+                if let Some(previous) = &previous {
+                    match cpu::cpus_load(previous, &current) {
+                        Ok(loads) => {
+                            if tx.send(loads).is_err() {
+                                break;
+                            }
+                        }
+                        Err(why) => tracing::error!("Could not compute CPU loads: {}", why),
+                    }
+                }
+                previous = Some(current);
+            }
+        });
+        LoadSampler { loads }
+    }
+
+    pub fn current(&self) -> Vec<(usize, f32)> {
+        let loads = self.loads.borrow().clone();
+        if !loads.is_empty() {
+            return loads;
+        }
+        // No sample yet, every CPU looks idle
+        let cpus_n = cpu::cpus_num().unwrap_or(1) as usize;
+        (0..cpus_n).map(|cpu| (cpu, 0.0)).collect()
+    }
+}
@@ -1,23 +1,37 @@
-use std::{os::unix::fs::PermissionsExt, time::Duration};
+use std::{path::PathBuf, sync::Arc};
 
-use clap::{Parser, arg};
-use tokio::{
-    signal::unix::{Signal, SignalKind},
-    task::JoinSet,
+use clap::Parser;
+use tokio::{signal::unix::SignalKind, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+
+use gaimoded::{
+    cfg, journal, listener, load, optimizer, paths::SystemPaths, proc_events, socket, utils,
+    watcher,
 };
 
-mod cfg;
-mod cpu;
-mod io;
-mod listener;
-mod optimizer;
-mod scheduler;
-mod utils;
+// Events kept for subscribers that fall behind
+const EVENTS_CAPACITY: usize = 64;
 
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(long)]
     forked: bool,
+
+    // Ignored when systemd passes the socket in
+    #[arg(long, env = gaiproto::SOCKET_PATH_ENV, default_value = gaiproto::DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+
+    // Where sysfs is mounted, only worth changing for testing
+    #[arg(long, default_value = "/sys")]
+    sys_root: PathBuf,
+
+    // Where procfs is mounted, only worth changing for testing
+    #[arg(long, default_value = "/proc")]
+    proc_root: PathBuf,
+
+    // Original system settings are journaled here until they are restored
+    #[arg(long, default_value = "/var/lib/gaimoded")]
+    state_dir: PathBuf,
 }
 
 #[tokio::main]
@@ -28,42 +42,99 @@ async fn main() {
         .expect("Wasn't able to set up SIGINT handler"); // CTRL+C sends this
 
     let args = Args::parse();
-    if args.forked {
-        if let Err(why) = utils::daemonize() {
-            eprintln!("Failed to daemonize: {}", why);
-            return;
-        }
+    if args.forked
+        && let Err(why) = utils::daemonize()
+    {
+        eprintln!("Failed to daemonize: {}", why);
+        return;
     }
 
     tracing_subscriber::fmt().pretty().init();
 
-    let mut path = std::env::temp_dir();
-    path.push(utils::UDS_FILENAME);
-
-    // Temp trick
-    if path.exists() {
-        std::fs::remove_file(&path).unwrap();
+    let socket = match socket::open(&args.socket) {
+        Ok(socket) => socket,
+        Err(why) => {
+            tracing::error!("Could not open socket: {}", why);
+            return;
+        }
+    };
+
+    let paths = SystemPaths::new(args.sys_root, args.proc_root);
+    if !paths.is_default() {
+        tracing::info!(
+            "Using sysfs at {} and procfs at {}",
+            paths.sys_root.display(),
+            paths.proc_root.display()
+        );
     }
 
-    let listener = tokio::net::UnixListener::bind(&path).expect("UDS creation failed");
-    let perms = std::fs::Permissions::from_mode(0x666);
-    std::fs::set_permissions(&path, perms).expect("chmod failed");
+    // Before anything is read, so settings left changed by a crash aren't taken as the originals
+    let journal = match journal::Journal::open(&args.state_dir) {
+        Ok(mut journal) => {
+            match journal.replay() {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Restored {} settings left changed by the last run", n),
+                Err(why) => tracing::error!("Could not replay the state journal: {}", why),
+            }
+            Some(journal)
+        }
+        Err(why) => {
+            tracing::warn!(
+                "No state journal in {}, a crash would leave system settings changed: {}",
+                args.state_dir.display(),
+                why
+            );
+            None
+        }
+    };
 
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<utils::Commands>();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<utils::Commands>();
 
-    let cfg = cfg::get_cfg().unwrap_or_else(|_| cfg::Settings::default());
-    let mut optimizer = optimizer::Optimizer::new(cfg);
-    let mut listener = listener::UdsListener::new(listener);
+    let cfg = cfg::get_cfg().unwrap_or_else(|why| {
+        tracing::error!("Could not read the settings, using the defaults: {}", why);
+        cfg::Settings::default()
+    });
+    let (events_tx, _) = tokio::sync::broadcast::channel::<gaiproto::Event>(EVENTS_CAPACITY);
+
+    let (access, limits) = (cfg.access.clone(), cfg.listener.clone());
+    let watcher_cfg = cfg.watcher.clone();
+    let watch_rules = Arc::new(watcher::Rules::compile(&watcher_cfg).unwrap_or_else(|why| {
+        tracing::error!("Invalid watcher rules, none are used: {}", why);
+        watcher::Rules::default()
+    }));
+    let loads = load::LoadSampler::spawn(&paths);
+    let mut optimizer =
+        optimizer::Optimizer::new(cfg, paths.clone(), events_tx.clone(), loads, journal);
+    let mut listener = listener::UdsListener::new(
+        socket.listener,
+        optimizer.capabilities(),
+        events_tx,
+        access,
+        limits,
+        paths.clone(),
+        watch_rules.clone(),
+    );
+
+    let shutdown = CancellationToken::new();
+    let optimizer_task = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { optimizer.run(rx, shutdown).await }
+    });
 
     let mut tasks_set = JoinSet::new();
-    tasks_set.spawn(async move {
-        loop {
-            if let Err(why) = optimizer.process(&mut rx).await {
-                tracing::error!("Failed to process optimizations: {}", why);
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // Without it exits and new children are only noticed by polling
+    match proc_events::ProcConnector::open() {
+        Ok(connector) => {
+            tasks_set.spawn(proc_events::forward(connector, tx.clone()));
         }
-    });
+        Err(why) => tracing::warn!("Process events unavailable, polling instead: {}", why),
+    }
+
+    if watcher_cfg.enabled {
+        let watcher = watcher::Watcher::new(watch_rules, paths, &watcher_cfg, tx.clone());
+        tasks_set.spawn(watcher.run());
+    }
 
     tasks_set.spawn(async move {
         loop {
@@ -83,8 +154,16 @@ async fn main() {
     }
 
     tasks_set.shutdown().await;
+    // Let the optimizer put everything back before the process exits
+    shutdown.cancel();
+    if let Err(why) = optimizer_task.await {
+        tracing::error!("Optimizer task failed: {}", why);
+    }
 
-    if let Err(why) = nix::unistd::unlink(&path) {
+    // systemd keeps its socket around for the next start
+    if let Some(path) = socket.owned_path
+        && let Err(why) = nix::unistd::unlink(&path)
+    {
         tracing::error!("Wasn't able to unlink UDS file: {}", why);
     }
 }
@@ -1,38 +1,68 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    os::fd::OwnedFd,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
 
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::{
+    sync::{broadcast, mpsc::UnboundedReceiver},
+    time::MissedTickBehavior,
+};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    cfg, cpu, io, scheduler,
+    auth, cfg, cpu, io,
+    journal::Journal,
+    load::LoadSampler,
+    paths::SystemPaths,
+    pidfd,
+    proc_events::ProcEvent,
+    profile::{self, Profiles},
+    scheduler, steam,
+    transaction::Transaction,
     utils::{self},
 };
 
-#[allow(dead_code)]
+// How often processes are checked for having exited
+const DEAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 struct State {
     path: PathBuf,
     governor: String,
 }
-impl Default for State {
-    fn default() -> Self {
-        Self {
-            path: PathBuf::new(),
-            governor: String::new(),
-        }
-    }
+
+// What a thread looked like before it was optimized
+#[derive(Default)]
+struct ThreadState {
+    niceness: Option<i32>,
+    ioprio: Option<i32>, // Raw value, the class is restored too
+    aff_mask: Option<libc::cpu_set_t>,
 }
 
-#[allow(dead_code)]
+#[derive(Default)]
 struct ProcessState {
-    niceness: Option<i32>,
-    ioniceness: Option<i32>,
-    aff_mask: Option<libc::cpu_set_t>, // Store main thread affinity mask
+    threads: HashMap<nix::unistd::Pid, ThreadState>, // By TID, the main thread's is the PID
+    pinned_cpu: Option<usize>,
+    // The explicitly optimized process this one descends from, None if it is one itself
+    root: Option<nix::unistd::Pid>,
+    // Keeps the PID from being mistaken for a recycled one, None on kernels without pidfd
+    pidfd: Option<OwnedFd>,
+    role: steam::Role,
+    owner: Option<nix::unistd::Uid>, // Descendants are only followed if they have the same one
+    profile: profile::Selected,      // Its knobs are applied and reset with these settings
 }
-impl Default for ProcessState {
-    fn default() -> Self {
-        Self {
-            niceness: None,
-            ioniceness: None,
-            aff_mask: None,
+
+impl ProcessState {
+    fn is_alive(&self, pid: nix::unistd::Pid) -> bool {
+        if let Some(fd) = &self.pidfd {
+            return !pidfd::has_exited(fd);
+        }
+        match nix::sys::signal::kill(pid, None) {
+            Ok(_) => true,                         // процесс жив
+            Err(nix::errno::Errno::EPERM) => true, // жив, но нет прав
+            Err(_) => false,
         }
     }
 }
@@ -41,240 +71,1371 @@ impl Default for ProcessState {
 pub struct Optimizer {
     old_sys_state: Option<Vec<State>>, // p
     processes: HashMap<nix::unistd::Pid, ProcessState>,
+    // Descendants reset on request, they are not picked up again
+    released: HashSet<nix::unistd::Pid>,
+    // Wine games whose wineserver is still to be followed: game, WINEPREFIX and tree root
+    pending_wine: Vec<(nix::unistd::Pid, String, nix::unistd::Pid)>,
     is_optimized: bool,
-    settings: cfg::Settings,
+    settings: Arc<cfg::Settings>, // Shared with knob work on the blocking pool
+    profiles: Arc<Profiles>,
+    paths: Arc<SystemPaths>,
+    events: broadcast::Sender<gaiproto::Event>,
+    loads: LoadSampler,
+    // None when the state directory is unusable, system changes then don't survive a crash
+    journal: Option<Arc<Mutex<Journal>>>, // Written from the blocking pool
 }
 
 impl Optimizer {
-    pub fn new(settings: cfg::Settings) -> Self {
+    pub fn new(
+        settings: cfg::Settings,
+        paths: SystemPaths,
+        events: broadcast::Sender<gaiproto::Event>,
+        loads: LoadSampler,
+        journal: Option<Journal>,
+    ) -> Self {
+        let profiles = Profiles::compile(&settings).unwrap_or_else(|why| {
+            tracing::error!("Invalid profiles, none are used: {}", why);
+            Profiles::base_only(&settings)
+        });
         Self {
             old_sys_state: None,
             processes: HashMap::new(),
+            released: HashSet::new(),
+            pending_wine: Vec::new(),
             is_optimized: false,
-            settings,
+            settings: Arc::new(settings),
+            profiles: Arc::new(profiles),
+            paths: Arc::new(paths),
+            events,
+            loads,
+            journal: journal.map(|journal| Arc::new(Mutex::new(journal))),
         }
     }
 
-    fn optimize_cpu(&mut self) -> anyhow::Result<()> {
-        if self.settings.cpu_governor.enabled {
-            if !cpu::is_gov_available(cpu::PERF_GOV)? {
-                return Err(anyhow::anyhow!(
-                    "Your policies do not support 'Performance' governor"
-                ));
-            }
-
-            let govs = cpu::get_govs()?;
+    fn emit(&self, event: gaiproto::Event) {
+        // Fails only when nobody is subscribed
+        let _ = self.events.send(event);
+    }
 
-            let mut new_old_global_state = Vec::new();
-            new_old_global_state.reserve(govs.len());
-            for (path, gov) in govs.into_iter() {
-                let mut state = State::default();
-                state.governor = gov;
-                state.path = path;
-                new_old_global_state.push(state);
+    // Knobs that are enabled and can be applied on this machine, as gaiproto CAP_* bits
+    pub fn capabilities(&self) -> u32 {
+        // A knob counts if any profile may apply it
+        let mut caps = 0;
+        for settings in self.profiles.all_settings() {
+            if settings.niceness.enabled {
+                caps |= gaiproto::CAP_NICENESS;
             }
+            if settings.ioniceness.enabled {
+                caps |= gaiproto::CAP_IONICENESS;
+            }
+            if settings.cpu_affinity.enabled {
+                caps |= gaiproto::CAP_CPU_AFFINITY;
+            }
+            if settings.cpu_governor.enabled
+                && cpu::is_gov_available(&self.paths, &settings.cpu_governor.optimized_type)
+                    .unwrap_or(false)
+            {
+                caps |= gaiproto::CAP_CPU_GOVERNOR;
+            }
+        }
+        caps
+    }
 
-            cpu::set_gov_all(cpu::PERF_GOV)?;
-            self.old_sys_state = Some(new_old_global_state);
+    async fn optimize_cpu(&mut self, governor: &str) -> anyhow::Result<()> {
+        if self.old_sys_state.is_some() {
+            // Already switched by an earlier process, the first profile's governor stays
+            return Ok(());
+        }
+        let (paths, journal) = (self.paths.clone(), self.journal.clone());
+        let target = governor.to_owned();
+        let old_state =
+            blocking(move || switch_governors(&paths, journal.as_deref(), &target)).await?;
+        for state in &old_state {
+            self.emit(gaiproto::Event::GovernorChanged {
+                policy: state.path.to_string_lossy().into_owned(),
+                governor: governor.to_owned(),
+            });
+        }
+        self.old_sys_state = Some(old_state);
+        Ok(())
+    }
+    async fn reset_cpu(&mut self) -> anyhow::Result<()> {
+        let Some(old_state) = self.old_sys_state.take() else {
+            return Ok(());
+        };
+        let journal = self.journal.clone();
+        let old_state = blocking(move || {
+            restore_governors(&old_state, journal.as_deref())?;
+            Ok(old_state)
+        })
+        .await?;
+        for state in old_state {
+            self.emit(gaiproto::Event::GovernorChanged {
+                policy: state.path.to_string_lossy().into_owned(),
+                governor: state.governor.trim().to_owned(),
+            });
         }
         Ok(())
     }
-    fn reset_cpu(&mut self) -> anyhow::Result<()> {
-        if self.settings.cpu_governor.enabled {
-            if let Some(old_state) = self.old_sys_state.as_ref() {
-                for state in old_state {
-                    cpu::set_gov(&state.path, &state.governor)?;
+
+    async fn add_process(
+        &mut self,
+        pid: nix::unistd::Pid,
+        pidfd: Option<OwnedFd>,
+        root: Option<nix::unistd::Pid>,
+        requested: Option<String>,
+    ) -> gaiproto::Response {
+        if let Some(name) = &requested
+            && self.profiles.get(name).is_none()
+        {
+            return gaiproto::Response::Error {
+                code: gaiproto::E_INVALID_REQUEST,
+                message: format!("There is no profile named '{}'", name),
+            };
+        }
+        if self.processes.contains_key(&pid) {
+            // Saving its state again would record our own values as the originals
+            tracing::debug!("Process {} is already optimized", pid);
+            return gaiproto::Response::Ok(Vec::new());
+        }
+        let pidfd = match pidfd {
+            Some(fd) => match pidfd::pid_of(&fd) {
+                Ok(Some(owner)) if owner == pid => Some(fd),
+                Ok(None) => {
+                    return gaiproto::Response::Error {
+                        code: gaiproto::E_NOT_FOUND,
+                        message: format!("Process {} has already exited", pid),
+                    };
                 }
+                _ => {
+                    return gaiproto::Response::Error {
+                        code: gaiproto::E_INVALID_REQUEST,
+                        message: format!("Attached pidfd does not refer to process {}", pid),
+                    };
+                }
+            },
+            // Only a PID was sent, pin the process down before touching anything
+            None => pidfd::open(pid).ok(),
+        };
+
+        // Descendants are optimized like the process they came from unless a profile matches them
+        let inherited = root
+            .and_then(|root| self.processes.get(&root))
+            .map(|state| state.profile.clone());
+        let (profiles, paths) = (self.profiles.clone(), self.paths.clone());
+        let saved = blocking(move || {
+            let profile = select_profile(&paths, pid, &profiles, requested.as_deref(), inherited);
+            let settings = profile.settings.clone();
+            let mut pstate = ProcessState {
+                pidfd,
+                root,
+                profile,
+                ..Default::default()
+            };
+            save_process_state(&paths, pid, &settings, &mut pstate)?;
+            pstate.owner = auth::process_owner(&paths, pid).ok();
+            let (role, prefix) = classify_process(&paths, pid, &settings);
+            pstate.role = role;
+            Ok((pstate, prefix))
+        })
+        .await;
+        let (pstate, prefix) = match saved {
+            Ok(saved) => saved,
+            Err(why) => {
+                tracing::error!("Failed to read process state: {}", why);
+                return gaiproto::Response::Error {
+                    code: gaiproto::E_NOT_FOUND,
+                    message: format!("Could not read state of process {}: {}", pid, why),
+                };
             }
+        };
+
+        if let Some(name) = &pstate.profile.name {
+            tracing::info!("Process {} uses profile '{}'", pid, name);
         }
-        Ok(())
+        let settings = pstate.profile.settings.clone();
+        let mut results = Vec::new();
+        if settings.cpu_governor.enabled {
+            results.push(knob_result(
+                gaiproto::Knob::CpuGovernor,
+                self.optimize_cpu(&settings.cpu_governor.optimized_type)
+                    .await,
+            ));
+        }
+        let paths = self.paths.clone();
+        let loads = self.loads.current();
+        let avoid = self.game_cpus(root.unwrap_or(pid));
+        let applied = blocking(move || {
+            let mut pstate = pstate;
+            let results = optimize_process(&paths, pid, &settings, &mut pstate, loads, &avoid);
+            Ok((pstate, results))
+        })
+        .await;
+        // Wrappers may get no knobs at all, they are still followed for the tree's sake
+        let (pstate, untouched) = match applied {
+            Ok((pstate, knobs)) => {
+                let untouched = knobs.is_empty();
+                results.extend(knobs);
+                (pstate, untouched)
+            }
+            Err(why) => {
+                // Nothing else holds the governor switched if this was the first process
+                if self.processes.is_empty()
+                    && let Err(why) = self.reset_cpu().await
+                {
+                    tracing::error!("Failed to restore governors: {}", why);
+                }
+                return gaiproto::Response::Error {
+                    code: gaiproto::E_INTERNAL,
+                    message: why.to_string(),
+                };
+            }
+        };
+
+        if untouched || results.iter().any(|r| r.error.is_none()) {
+            self.processes.insert(pid, pstate);
+            self.is_optimized = true;
+            self.emit(gaiproto::Event::ProcessOptimized { pid: pid.as_raw() });
+            self.settle_role(pid, prefix).await;
+        }
+        knobs_response(results)
     }
 
-    fn add_process(&mut self, pid: nix::unistd::Pid) -> anyhow::Result<()> {
-        let mut pstate = ProcessState::default();
+    // Follow-ups once `pid` is tracked in its role
+    async fn settle_role(&mut self, pid: nix::unistd::Pid, prefix: Option<String>) {
+        let Some(state) = self.processes.get(&pid) else {
+            return;
+        };
+        let (role, tree) = (state.role, state.root.unwrap_or(pid));
+        if role != steam::Role::Other {
+            tracing::info!("Process {} is a {:?}", pid, role);
+        }
+        if role.is_pinned() {
+            self.keep_off_game_cpus(tree).await;
+        }
+        if role == steam::Role::Game
+            && let Some(prefix) = prefix
+        {
+            self.pending_wine.push((pid, prefix, tree));
+        }
+    }
 
-        if self.settings.niceness.enabled {
-            let old_niceness: i32 = scheduler::process_niceness(pid)?;
-            pstate.niceness = Some(old_niceness);
+    // Cores the games of the tree rooted at `tree` are pinned to
+    fn game_cpus(&self, tree: nix::unistd::Pid) -> Vec<usize> {
+        let mut cpus = self
+            .processes
+            .iter()
+            .filter(|(pid, state)| state.role.is_pinned() && state.root.unwrap_or(**pid) == tree)
+            .filter_map(|(_, state)| state.pinned_cpu)
+            .collect::<Vec<_>>();
+        cpus.sort_unstable();
+        cpus.dedup();
+        cpus
+    }
+
+    // Moves the wrappers and wineserver of a tree off the cores its games got since
+    async fn keep_off_game_cpus(&self, tree: nix::unistd::Pid) {
+        let avoid = self.game_cpus(tree);
+        let others = self
+            .processes
+            .iter()
+            .filter(|(pid, state)| {
+                !state.role.is_pinned()
+                    && state.root.unwrap_or(**pid) == tree
+                    && state.profile.settings.cpu_affinity.enabled
+            })
+            .map(|(pid, _)| *pid)
+            .collect::<Vec<_>>();
+        if avoid.is_empty() || others.is_empty() {
+            return;
         }
-        if self.settings.ioniceness.enabled {
-            let old_ioniceness: i32 = io::process_io_niceness(pid)?;
-            pstate.ioniceness = Some(old_ioniceness);
+        let paths = self.paths.clone();
+        let _ = blocking(move || {
+            for pid in others {
+                if let Err(why) = keep_off(&paths, pid, &avoid) {
+                    tracing::debug!(
+                        "Could not move process {} off the game's cores: {}",
+                        pid,
+                        why
+                    );
+                }
+            }
+            Ok(())
+        })
+        .await;
+    }
+
+    // What threads of `pid` started later get: whether they are boosted and the cores they stay off
+    fn thread_policy(&self, pid: nix::unistd::Pid, state: &ProcessState) -> (bool, Vec<usize>) {
+        let avoid = match state.pinned_cpu {
+            Some(cpu) => vec![cpu],
+            None if state.role.is_pinned() => Vec::new(),
+            None => self.game_cpus(state.root.unwrap_or(pid)),
+        };
+        (state.role.is_boosted(), avoid)
+    }
+
+    // An exec can turn a launcher into the game or the other way around
+    async fn reclassify(&mut self, pid: nix::unistd::Pid) {
+        let (settings, paths) = (self.settings.clone(), self.paths.clone());
+        let Ok((role, prefix)) =
+            blocking(move || Ok(classify_process(&paths, pid, &settings))).await
+        else {
+            return;
+        };
+        if self
+            .processes
+            .get(&pid)
+            .is_none_or(|state| state.role == role)
+        {
+            return;
         }
-        if self.settings.cpu_affinity.enabled {
-            pstate.aff_mask = Some(cpu::get_aff_mask(pid)?);
+        let Some(mut pstate) = self.processes.remove(&pid) else {
+            return;
+        };
+        let avoid = self.game_cpus(pstate.root.unwrap_or(pid));
+        let loads = self.loads.current();
+        let (settings, paths) = (pstate.profile.settings.clone(), self.paths.clone());
+        let applied = blocking(move || {
+            // Knobs of the old role are undone first, the new one may not use all of them
+            reset_process(&paths, pid, &pstate, &settings)?;
+            pstate.role = role;
+            pstate.pinned_cpu = None;
+            optimize_process(&paths, pid, &settings, &mut pstate, loads, &avoid);
+            Ok(pstate)
+        })
+        .await;
+        match applied {
+            Ok(pstate) => {
+                self.processes.insert(pid, pstate);
+                self.settle_role(pid, prefix).await;
+            }
+            Err(why) => tracing::error!("Failed to re-apply knobs to process {}: {}", pid, why),
         }
+    }
+
+    // Follows the wineservers of Wine games tracked since the last look
+    async fn attach_wineservers(&mut self) {
+        for (game, prefix, tree) in std::mem::take(&mut self.pending_wine) {
+            let paths = self.paths.clone();
+            let found = blocking(move || {
+                let owner = auth::process_owner(&paths, game)?;
+                steam::find_wineserver(&paths, &prefix, owner)
+            })
+            .await;
+            match found {
+                Ok(Some(server)) => {
+                    self.adopt(server, tree, false).await;
+                }
+                Ok(None) => tracing::debug!("No wineserver found for process {}", game),
+                Err(why) => tracing::debug!("Wineserver lookup for process {}: {}", game, why),
+            }
+        }
+    }
+
+    // Resets the process and the descendants it brought in
+    async fn remove_process(&mut self, pid: nix::unistd::Pid) -> gaiproto::Response {
+        if self
+            .processes
+            .get(&pid)
+            .is_some_and(|state| state.root.is_some())
+        {
+            self.released.insert(pid);
+        }
+        let response = self.remove_tracked(pid).await;
+
+        let adopted = self
+            .processes
+            .iter()
+            .filter(|(_, state)| state.root == Some(pid))
+            .map(|(child, _)| *child)
+            .collect::<Vec<_>>();
+        for child in adopted {
+            if let gaiproto::Response::Error { message, .. } = self.remove_tracked(child).await {
+                tracing::debug!("Descendant {} of {}: {}", child, pid, message);
+            }
+        }
+        response
+    }
 
-        match optimize_process(pid, &self.settings) {
+    async fn remove_tracked(&mut self, pid: nix::unistd::Pid) -> gaiproto::Response {
+        let Some(state) = self.processes.remove(&pid) else {
+            return gaiproto::Response::Error {
+                code: gaiproto::E_NOT_FOUND,
+                message: format!("Process {} is not optimized", pid),
+            };
+        };
+        if !state.is_alive(pid) {
+            // The PID may belong to someone else by now, leave it alone
+            self.emit(gaiproto::Event::ProcessDied { pid: pid.as_raw() });
+            return gaiproto::Response::Error {
+                code: gaiproto::E_NOT_FOUND,
+                message: format!("Process {} has exited", pid),
+            };
+        }
+        let (settings, paths) = (state.profile.settings.clone(), self.paths.clone());
+        match blocking(move || reset_process(&paths, pid, &state, &settings)).await {
             Ok(_) => {
-                self.processes.insert(pid, pstate);
-                return Ok(());
+                self.emit(gaiproto::Event::ProcessReset { pid: pid.as_raw() });
+                gaiproto::Response::Ok(Vec::new())
             }
-            Err(why) => {
-                tracing::error!("Failed to optimize process");
-                return Err(why);
+            Err(why) => gaiproto::Response::Error {
+                code: gaiproto::E_INTERNAL,
+                message: why.to_string(),
+            },
+        }
+    }
+
+    pub fn status(&self) -> gaiproto::StatusReport {
+        let governors = self
+            .old_sys_state
+            .iter()
+            .flatten()
+            .map(|state| gaiproto::GovernorStatus {
+                policy: state.path.to_string_lossy().into_owned(),
+                governor: state.governor.trim().to_owned(),
+            })
+            .collect();
+
+        let mut processes = self
+            .processes
+            .iter()
+            .map(|(pid, state)| {
+                // Reported values are the main thread's
+                let main = state.threads.get(pid);
+                gaiproto::ProcessStatus {
+                    pid: pid.as_raw(),
+                    niceness: main.and_then(|t| t.niceness),
+                    ioniceness: main.and_then(|t| t.ioprio).map(io::ioprio_prio_data),
+                    affinity: main
+                        .and_then(|t| t.aff_mask.as_ref())
+                        .map(|mask| cpu::mask_cpus(mask).into_iter().map(|c| c as u32).collect()),
+                    pinned_cpu: state.pinned_cpu.map(|cpu| cpu as u32),
+                    profile: state.profile.name.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+        processes.sort_by_key(|p| p.pid);
+
+        gaiproto::StatusReport {
+            is_optimized: self.is_optimized,
+            governors,
+            processes,
+        }
+    }
+
+    // One process failing does not keep the others from being reset
+    async fn reset_processes(&mut self) -> anyhow::Result<()> {
+        let (processes, paths) = (std::mem::take(&mut self.processes), self.paths.clone());
+        let outcomes = blocking(move || Ok(reset_each_process(&paths, processes))).await?;
+        let mut failed = Vec::new();
+        for (pid, outcome) in outcomes {
+            match outcome {
+                Ok(_) => self.emit(gaiproto::Event::ProcessReset { pid: pid.as_raw() }),
+                Err(why) => {
+                    tracing::error!("Failed to reset process {}: {}", pid, why);
+                    failed.push(format!("process {}: {}", pid, why));
+                }
+            }
+        }
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!("Could not reset {}", failed.join("; ")))
+    }
+
+    // Governors stay switched until the last process is gone, like for single resets
+    async fn reset_owned_by(&mut self, uid: nix::unistd::Uid) -> gaiproto::Response {
+        let (tracked, paths) = (
+            self.processes.keys().copied().collect::<Vec<_>>(),
+            self.paths.clone(),
+        );
+        let owned = blocking(move || {
+            Ok(tracked
+                .into_iter()
+                .filter(
+                    |pid| matches!(auth::process_owner(&paths, *pid), Ok(owner) if owner == uid),
+                )
+                .collect::<Vec<_>>())
+        })
+        .await
+        .unwrap_or_default();
+        tracing::info!("Resetting {} processes of uid {}", owned.len(), uid);
+
+        let mut failed = Vec::new();
+        for pid in owned {
+            if let gaiproto::Response::Error { code, message } = self.remove_process(pid).await
+                && code != gaiproto::E_NOT_FOUND
+            {
+                failed.push(message);
             }
         }
+        if failed.is_empty() {
+            return gaiproto::Response::Ok(Vec::new());
+        }
+        gaiproto::Response::Error {
+            code: gaiproto::E_INTERNAL,
+            message: failed.join("; "),
+        }
     }
 
-    fn reset_processes(&mut self) -> anyhow::Result<()> {
-        for (process, state) in self.processes.drain() {
-            reset_process(process, state, &self.settings)?;
+    // Governors go back once the last process is gone, before whoever removed it hears back
+    async fn reset_if_idle(&mut self) {
+        if self.is_optimized
+            && self.processes.is_empty()
+            && let Err(why) = self.reset().await
+        {
+            tracing::error!("Failed to reset optimizations: {}", why);
         }
-        Ok(())
     }
 
-    fn reset(&mut self) -> anyhow::Result<()> {
+    async fn reset(&mut self) -> anyhow::Result<()> {
         tracing::info!("Resetting all optimizations");
-        if self.is_optimized {
-            self.is_optimized = false;
-            self.reset_processes()?;
-            self.reset_cpu()?;
+        if !self.is_optimized {
+            return Ok(());
         }
-        Ok(())
+        self.is_optimized = false;
+        // The governors go back even if some process could not be reset
+        let failed = [self.reset_processes().await, self.reset_cpu().await]
+            .into_iter()
+            .filter_map(Result::err)
+            .map(|why| why.to_string())
+            .collect::<Vec<_>>();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(failed.join("; ")))
     }
 
-    fn clear_dead_pids(&mut self) -> bool {
-        let mut has_removed = false;
-        self.processes.retain(|pid, _| {
-            // let res = unsafe { nix::libc::kill(pid.as_raw(), 0) }
-            match nix::sys::signal::kill(*pid, None) {
-                Ok(_) => return true,                         // процесс жив
-                Err(nix::errno::Errno::EPERM) => return true, // жив, но нет прав
-                Err(_) => {
-                    has_removed = true;
-                    return false;
+    // Optimizes threads the tracked processes started since the last look
+    async fn rescan_threads(&mut self) {
+        let known = self
+            .processes
+            .iter()
+            .map(|(pid, state)| {
+                (
+                    *pid,
+                    state.threads.keys().copied().collect(),
+                    self.thread_policy(*pid, state),
+                    state.profile.settings.clone(),
+                )
+            })
+            .collect::<Vec<(nix::unistd::Pid, HashSet<_>, _, _)>>();
+        if known.is_empty() {
+            return;
+        }
+
+        let paths = self.paths.clone();
+        let scanned = blocking(move || {
+            Ok(known
+                .into_iter()
+                .filter_map(|(pid, known, (boosted, avoid), settings)| {
+                    // Fails only when the process is gone, the liveness check deals with that
+                    let tids = utils::get_process_tasks(&paths, pid).ok()?;
+                    let tids = tids
+                        .into_iter()
+                        .map(|tid| nix::unistd::Pid::from_raw(tid as i32))
+                        .collect::<HashSet<_>>();
+                    let new = tids
+                        .iter()
+                        .filter(|tid| !known.contains(tid))
+                        .map(|tid| {
+                            optimize_thread(*tid, &settings, boosted, &avoid);
+                            // They inherited optimized values at creation, so reset gives them defaults
+                            (*tid, ThreadState::default())
+                        })
+                        .collect::<Vec<_>>();
+                    Some((pid, tids, new))
+                })
+                .collect::<Vec<_>>())
+        })
+        .await;
+        let scanned = match scanned {
+            Ok(scanned) => scanned,
+            Err(why) => {
+                tracing::error!("Thread rescan failed: {}", why);
+                return;
+            }
+        };
+
+        for (pid, tids, new) in scanned {
+            let Some(state) = self.processes.get_mut(&pid) else {
+                continue;
+            };
+            // Exited threads are forgotten, their TIDs may come back as new threads
+            state.threads.retain(|tid, _| tids.contains(tid));
+            if !new.is_empty() {
+                tracing::debug!("Optimized {} new threads of process {}", new.len(), pid);
+            }
+            state.threads.extend(new);
+        }
+    }
+
+    // Starts tracking children of tracked processes, down to the last generation
+    async fn adopt_children(&mut self) {
+        let mut tried = HashSet::new();
+        loop {
+            let tracked = self
+                .processes
+                .iter()
+                .map(|(pid, state)| (*pid, state.root.unwrap_or(*pid)))
+                .collect::<Vec<_>>();
+            let paths = self.paths.clone();
+            let found = blocking(move || Ok(find_children(&paths, tracked))).await;
+            let found = match found {
+                Ok(found) => found,
+                Err(why) => {
+                    tracing::error!("Process tree scan failed: {}", why);
+                    return;
                 }
             };
-        });
-        has_removed
+
+            let mut adopted = 0;
+            for (child, root) in found {
+                if tried.insert(child) && self.adopt(child, root, false).await {
+                    adopted += 1;
+                }
+            }
+            if adopted == 0 {
+                break;
+            }
+        }
+
+        // Released PIDs that are gone could come back as unrelated processes
+        let paths = self.paths.clone();
+        self.released.retain(|pid| paths.process(*pid).exists());
     }
 
-    pub async fn process(
+    // Tracks `child` under `root`, false if it is not tracked afterwards.
+    // `forked_optimized` is for children that inherited optimized values and have nothing to restore.
+    async fn adopt(
         &mut self,
-        rx: &mut UnboundedReceiver<utils::Commands>,
-    ) -> anyhow::Result<()> {
-        if let Ok(command) = rx.try_recv() {
-            match command {
-                utils::Commands::OptimizeProcess(pid) => {
-                    if !self.is_optimized {
-                        if let Err(why) = self.optimize_cpu() {
-                            return Err(anyhow::anyhow!("Error optimizing CPU: {}", why));
-                        }
-                        self.is_optimized = true;
-                    }
+        child: nix::unistd::Pid,
+        root: nix::unistd::Pid,
+        forked_optimized: bool,
+    ) -> bool {
+        if self.processes.contains_key(&child) || self.released.contains(&child) {
+            return false;
+        }
+        // The root may be gone already, any process of its tree tells the owner
+        let Some(owner) = self
+            .processes
+            .iter()
+            .find(|(pid, state)| state.root.unwrap_or(**pid) == root)
+            .and_then(|(_, state)| state.owner)
+        else {
+            return false;
+        };
+        let paths = self.paths.clone();
+        let same_owner = blocking(move || auth::process_owner(&paths, child))
+            .await
+            .is_ok_and(|uid| uid == owner);
+        if !same_owner {
+            tracing::debug!("Not following process {}, it has another owner", child);
+            return false;
+        }
+        tracing::info!("Following process {} along with {}", child, root);
+        self.add_process(child, None, Some(root), None).await;
+        match self.processes.get_mut(&child) {
+            Some(state) => {
+                if forked_optimized {
+                    state
+                        .threads
+                        .values_mut()
+                        .for_each(|t| *t = ThreadState::default());
+                }
+                true
+            }
+            None => false,
+        }
+    }
 
-                    self.add_process(pid)?;
+    async fn handle_proc_event(&mut self, event: ProcEvent) {
+        match event {
+            ProcEvent::Fork { parent, pid, tgid } if pid == tgid => {
+                if !self.settings.process_tree.follow_children {
+                    return;
+                }
+                // Ownership is checked again on adoption, events are not trusted with it
+                let Some(root) = self
+                    .processes
+                    .get(&parent)
+                    .map(|s| s.root.unwrap_or(parent))
+                else {
+                    return;
+                };
+                self.adopt(pid, root, true).await;
+            }
+            ProcEvent::Fork { pid, tgid, .. } => {
+                let Some(((boosted, avoid), settings)) = self
+                    .processes
+                    .get(&tgid)
+                    .map(|s| (self.thread_policy(tgid, s), s.profile.settings.clone()))
+                else {
+                    return;
+                };
+                let optimized = blocking(move || {
+                    optimize_thread(pid, &settings, boosted, &avoid);
+                    Ok(())
+                })
+                .await;
+                if optimized.is_ok()
+                    && let Some(state) = self.processes.get_mut(&tgid)
+                {
+                    // Like rescanned threads, it started out with the optimized values
+                    state.threads.insert(pid, ThreadState::default());
                 }
-                utils::Commands::ResetProcess(pid) => {
-                    if let Some(state) = self.processes.remove(&pid) {
-                        reset_process(pid, state, &self.settings)?;
+            }
+            ProcEvent::Exec { pid } => {
+                let Some(state) = self.processes.get_mut(&pid) else {
+                    return;
+                };
+                tracing::debug!("Process {} called exec", pid);
+                state.threads.retain(|tid, _| *tid == pid);
+                if self.settings.steam.enabled {
+                    self.reclassify(pid).await;
+                }
+            }
+            // The PID can't be reused before the process is reaped, so it's still ours
+            ProcEvent::Exit { pid, tgid } if pid == tgid => {
+                if self.processes.remove(&pid).is_some() {
+                    tracing::info!("Process {} exited", pid.as_raw());
+                    self.emit(gaiproto::Event::ProcessDied { pid: pid.as_raw() });
+                }
+            }
+            ProcEvent::Exit { pid, tgid } => {
+                if let Some(state) = self.processes.get_mut(&tgid) {
+                    state.threads.remove(&pid);
+                }
+            }
+        }
+    }
+
+    fn clear_dead_pids(&mut self) -> bool {
+        let mut dead = Vec::new();
+        self.processes.retain(|pid, state| {
+            let alive = state.is_alive(*pid);
+            if !alive {
+                dead.push(*pid);
+            }
+            alive
+        });
+        for pid in &dead {
+            tracing::info!("Process {} exited", pid.as_raw());
+            self.emit(gaiproto::Event::ProcessDied { pid: pid.as_raw() });
+        }
+        !dead.is_empty()
+    }
+
+    // Serves commands as they arrive until `shutdown` fires, then undoes everything
+    pub async fn run(
+        &mut self,
+        mut rx: UnboundedReceiver<utils::Commands>,
+        shutdown: CancellationToken,
+    ) {
+        let mut liveness = tokio::time::interval(DEAD_CHECK_INTERVAL);
+        liveness.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let rescan_secs = self.settings.threads.rescan_interval_secs;
+        let rescan_enabled = rescan_secs > 0
+            && self.profiles.all_settings().any(|settings| {
+                settings.niceness.enabled
+                    || settings.ioniceness.enabled
+                    || settings.cpu_affinity.enabled
+            });
+        let mut rescan = tokio::time::interval(Duration::from_secs(rescan_secs.max(1)));
+        rescan.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let tree = &self.settings.process_tree;
+        let follow_children = tree.follow_children && tree.scan_interval_secs > 0;
+        let mut tree_scan =
+            tokio::time::interval(Duration::from_secs(tree.scan_interval_secs.max(1)));
+        tree_scan.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break,
+                command = rx.recv() => {
+                    let Some(command) = command else {
+                        break;
+                    };
+                    self.handle_command(command).await;
+                    // Serve a burst in one go instead of waking up for each command
+                    while let Ok(command) = rx.try_recv() {
+                        self.handle_command(command).await;
                     }
                 }
-                utils::Commands::ResetAll => self.reset()?,
+                _ = liveness.tick() => {
+                    self.clear_dead_pids();
+                }
+                _ = rescan.tick(), if rescan_enabled => {
+                    self.rescan_threads().await;
+                }
+                _ = tree_scan.tick(), if follow_children => {
+                    self.adopt_children().await;
+                }
+            }
+
+            if !self.pending_wine.is_empty() {
+                self.attach_wineservers().await;
             }
+
+            self.reset_if_idle().await;
         }
 
-        let _ = self.clear_dead_pids();
-        if self.is_optimized && self.processes.is_empty() {
-            self.reset()?;
+        if let Err(why) = self.graceful_shutdown().await {
+            tracing::error!("Shutdown failed: {}", why);
         }
+    }
 
-        Ok(())
+    async fn handle_command(&mut self, command: utils::Commands) {
+        match command {
+            utils::Commands::OptimizeProcess(pid, pidfd, profile, reply) => {
+                let response = self.add_process(pid, pidfd, None, profile).await;
+                let _ = reply.send(response);
+            }
+            utils::Commands::ResetProcess(pid, reply) => {
+                let response = self.remove_process(pid).await;
+                self.reset_if_idle().await;
+                let _ = reply.send(response);
+            }
+            // The whole batch is handled in one go, nothing else runs in between
+            utils::Commands::OptimizeBatch(pids, reply) => {
+                let mut results = Vec::with_capacity(pids.len());
+                for pid in pids {
+                    results.push(gaiproto::PidResult {
+                        pid: pid.as_raw(),
+                        response: self.add_process(pid, None, None, None).await,
+                    });
+                }
+                let _ = reply.send(results);
+            }
+            utils::Commands::ResetBatch(pids, reply) => {
+                let mut results = Vec::with_capacity(pids.len());
+                for pid in pids {
+                    results.push(gaiproto::PidResult {
+                        pid: pid.as_raw(),
+                        response: self.remove_process(pid).await,
+                    });
+                }
+                self.reset_if_idle().await;
+                let _ = reply.send(results);
+            }
+            utils::Commands::ProcEvent(event) => self.handle_proc_event(event).await,
+            utils::Commands::Status(reply) => {
+                let _ = reply.send(self.status());
+            }
+            utils::Commands::ResetAll(Some(uid), reply) => {
+                let response = self.reset_owned_by(uid).await;
+                self.reset_if_idle().await;
+                let _ = reply.send(response);
+            }
+            utils::Commands::ResetAll(None, reply) => {
+                let response = match self.reset().await {
+                    Ok(_) => gaiproto::Response::Ok(Vec::new()),
+                    Err(why) => gaiproto::Response::Error {
+                        code: gaiproto::E_INTERNAL,
+                        message: why.to_string(),
+                    },
+                };
+                let _ = reply.send(response);
+            }
+        }
     }
 
-    pub fn graceful_shutdown(&mut self) -> anyhow::Result<()> {
-        self.reset()?;
+    pub async fn graceful_shutdown(&mut self) -> anyhow::Result<()> {
+        self.reset().await?;
         Ok(())
     }
 }
 
 impl Drop for Optimizer {
+    // Only does anything if the loop did not get to shut down, the runtime may be gone by then
     fn drop(&mut self) {
-        if let Err(why) = self.graceful_shutdown() {
+        if !self.is_optimized {
+            return;
+        }
+        self.is_optimized = false;
+        let processes = std::mem::take(&mut self.processes);
+        for (pid, outcome) in reset_each_process(&self.paths, processes) {
+            if let Err(why) = outcome {
+                tracing::error!("Failed to reset process {}: {}", pid, why);
+            }
+        }
+        if let Some(old_state) = self.old_sys_state.take()
+            && let Err(why) = restore_governors(&old_state, self.journal.as_deref())
+        {
             tracing::error!("Shutdown failed: {}", why);
         }
     }
 }
 
+// Switches every policy to `governor`, returns what they had before
+fn switch_governors(
+    paths: &SystemPaths,
+    journal: Option<&Mutex<Journal>>,
+    governor: &str,
+) -> anyhow::Result<Vec<State>> {
+    if !cpu::is_gov_available(paths, governor)? {
+        return Err(anyhow::anyhow!(
+            "Your policies do not support '{}' governor",
+            governor
+        ));
+    }
+
+    let mut old_state = cpu::get_govs(paths)?
+        .into_iter()
+        .map(|(path, governor)| State { path, governor })
+        .collect::<Vec<_>>();
+
+    let mut journal = journal.map(lock);
+    if let Some(journal) = &mut journal {
+        for state in &mut old_state {
+            // A policy the last replay could not restore still has our governor, its
+            // original is the journaled one
+            if let Some(original) = journal.original(&state.path)? {
+                state.governor = original;
+            }
+            journal.record(&state.path, &state.governor)?;
+        }
+    }
+    // All policies or none, a half switched CPU would stay that way until the next start
+    let mut tx = Transaction::new();
+    for state in &old_state {
+        let switched = tx.step(
+            state.path.to_string_lossy(),
+            || cpu::set_gov(&state.path, governor),
+            || cpu::set_gov(&state.path, &state.governor),
+        );
+        if let Err(why) = switched {
+            let rolled_back = tx.rollback(why);
+            // Whatever is still switched is left for the journal replay
+            if rolled_back.complete
+                && let Some(journal) = &mut journal
+            {
+                let paths = old_state.iter().map(|state| state.path.clone());
+                journal.forget(&paths.collect::<Vec<_>>())?;
+            }
+            return Err(rolled_back.error);
+        }
+    }
+    tx.commit();
+    Ok(old_state)
+}
+
+// Tries every policy, only those put back leave the journal
+fn restore_governors(old_state: &[State], journal: Option<&Mutex<Journal>>) -> anyhow::Result<()> {
+    let mut restored = Vec::new();
+    let mut failed = Vec::new();
+    for state in old_state {
+        match cpu::set_gov(&state.path, &state.governor) {
+            Ok(_) => restored.push(state.path.clone()),
+            Err(why) => {
+                tracing::error!("Could not restore {}: {}", state.path.display(), why);
+                failed.push(format!("{}: {}", state.path.display(), why));
+            }
+        }
+    }
+    if let Some(journal) = journal {
+        lock(journal).forget(&restored)?;
+    }
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "Could not restore governors of {}",
+        failed.join("; ")
+    ))
+}
+
+// A panic while holding it can't leave the journal worse than a crash would
+fn lock(journal: &Mutex<Journal>) -> MutexGuard<'_, Journal> {
+    journal
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Resets the processes still alive, each one whatever happened to the others
+fn reset_each_process(
+    paths: &SystemPaths,
+    processes: HashMap<nix::unistd::Pid, ProcessState>,
+) -> Vec<(nix::unistd::Pid, anyhow::Result<()>)> {
+    processes
+        .into_iter()
+        .filter(|(process, state)| state.is_alive(*process))
+        .map(|(process, state)| {
+            let outcome = reset_process(paths, process, &state, &state.profile.settings);
+            (process, outcome)
+        })
+        .collect()
+}
+
 fn reset_process(
+    paths: &SystemPaths,
     pid: nix::unistd::Pid,
-    state: ProcessState,
+    state: &ProcessState,
     settings: &cfg::Settings,
 ) -> anyhow::Result<()> {
     tracing::info!("Resetting process: {}", pid.as_raw());
 
-    if settings.niceness.enabled {
-        if let Err(why) = scheduler::set_process_niceness(
-            pid,
-            state.niceness.unwrap_or(settings.niceness.default_value),
-        ) {
-            tracing::error!("Failed to reset process niceness: {}", why);
-        }
+    let default_mask = match settings.cpu_affinity.enabled {
+        true => Some(get_aff_default()?),
+        false => None,
+    };
+    for tid in utils::get_process_tasks(paths, pid)? {
+        let tid = nix::unistd::Pid::from_raw(tid as i32);
+        // Threads started after optimizing have nothing saved and get the defaults
+        let saved = state.threads.get(&tid);
+        reset_thread(tid, saved, settings, default_mask);
     }
+    Ok(())
+}
 
-    if settings.ioniceness.enabled {
-        if let Err(why) = io::set_process_io_niceness(
-            pid,
-            state
-                .ioniceness
-                .unwrap_or(settings.ioniceness.default_value),
-        ) {
-            tracing::error!("Failed to reset process I/O niceness: {}", why);
+// Errors are only logged, the remaining knobs and threads are still reset
+fn reset_thread(
+    tid: nix::unistd::Pid,
+    saved: Option<&ThreadState>,
+    settings: &cfg::Settings,
+    default_mask: Option<libc::cpu_set_t>,
+) {
+    if settings.niceness.enabled
+        && let Err(why) = restore_niceness(tid, saved, settings)
+    {
+        tracing::error!("Failed to reset niceness: {}", why);
+    }
+
+    if settings.ioniceness.enabled
+        && let Err(why) = restore_ioprio(tid, saved, settings)
+    {
+        tracing::error!("Failed to reset I/O niceness: {}", why);
+    }
+
+    if let Err(why) = restore_affinity(tid, saved, default_mask) {
+        tracing::error!("Could not reset thread {} affinity mask: {}", tid, why);
+    }
+}
+
+// The saved value, or the configured default for threads that have none
+fn restore_niceness(
+    tid: nix::unistd::Pid,
+    saved: Option<&ThreadState>,
+    settings: &cfg::Settings,
+) -> anyhow::Result<()> {
+    let niceness = saved
+        .and_then(|t| t.niceness)
+        .unwrap_or(settings.niceness.default_value);
+    scheduler::set_thread_niceness(tid, niceness)
+}
+
+fn restore_ioprio(
+    tid: nix::unistd::Pid,
+    saved: Option<&ThreadState>,
+    settings: &cfg::Settings,
+) -> anyhow::Result<()> {
+    match saved.and_then(|t| t.ioprio) {
+        Some(ioprio) => io::set_thread_ioprio(tid, ioprio),
+        None => io::set_thread_io_niceness(tid, settings.ioniceness.default_value),
+    }
+}
+
+// Nothing to do when there is neither a saved nor a default mask
+fn restore_affinity(
+    tid: nix::unistd::Pid,
+    saved: Option<&ThreadState>,
+    default_mask: Option<libc::cpu_set_t>,
+) -> anyhow::Result<()> {
+    match saved.and_then(|t| t.aff_mask).or(default_mask) {
+        Some(mask) => cpu::set_aff_mask(tid, mask),
+        None => Ok(()),
+    }
+}
+
+// Children of the tracked processes, paired with the root each one should be tracked under.
+// Only children of the same user are returned, a setuid child is not the user's to optimize.
+fn find_children(
+    paths: &SystemPaths,
+    tracked: Vec<(nix::unistd::Pid, nix::unistd::Pid)>,
+) -> Vec<(nix::unistd::Pid, nix::unistd::Pid)> {
+    let mut found = Vec::new();
+    for (pid, root) in tracked {
+        let (Ok(owner), Ok(children)) = (
+            auth::process_owner(paths, pid),
+            utils::process_children(paths, pid),
+        ) else {
+            continue;
+        };
+        for child in children {
+            if matches!(auth::process_owner(paths, child), Ok(uid) if uid == owner) {
+                found.push((child, root));
+            }
         }
     }
+    found
+}
 
-    if settings.cpu_affinity.enabled {
-        let tasks = &utils::get_process_tasks(pid)?; // 0 task is the process itself (main thread)
-        for task in tasks {
-            if let Err(why) = cpu::set_aff_mask(
-                nix::unistd::Pid::from_raw(*task as i32),
-                state.aff_mask.unwrap_or_else(|| get_aff_default().unwrap()),
-            ) {
-                tracing::error!("Could not reset process affinity mask: {}", why);
+// Runs sysfs/procfs work on the blocking pool so the runtime keeps serving clients
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(f).await?
+}
+
+fn save_process_state(
+    paths: &SystemPaths,
+    pid: nix::unistd::Pid,
+    settings: &cfg::Settings,
+    pstate: &mut ProcessState,
+) -> anyhow::Result<()> {
+    for tid in utils::get_process_tasks(paths, pid)? {
+        let tid = nix::unistd::Pid::from_raw(tid as i32);
+        match save_thread_state(tid, settings) {
+            Ok(thread) => {
+                pstate.threads.insert(tid, thread);
             }
+            // Other threads may exit while the list is walked, only the main one has to be there
+            Err(why) if tid != pid => tracing::debug!("Skipped thread {}: {}", tid, why),
+            Err(why) => return Err(why),
         }
     }
     Ok(())
 }
 
-fn optimize_process(pid: nix::unistd::Pid, settings: &cfg::Settings) -> anyhow::Result<()> {
-    tracing::info!("Optimizing process: {}", pid.as_raw());
-
-    // nicenessness
-    // We can kinda ignore an error, maybe if it fails this it doesnt fail to do any other optimizations
+fn save_thread_state(
+    tid: nix::unistd::Pid,
+    settings: &cfg::Settings,
+) -> anyhow::Result<ThreadState> {
+    let mut thread = ThreadState::default();
     if settings.niceness.enabled {
-        scheduler::set_process_niceness(pid, settings.niceness.optimized_value)?;
+        thread.niceness = Some(scheduler::process_niceness(tid)?);
     }
     if settings.ioniceness.enabled {
-        io::set_process_io_niceness(pid, settings.ioniceness.optimized_value)?;
+        thread.ioprio = Some(io::thread_ioprio(tid)?);
     }
-
-    // CPU Affinity
-    // Find the lowest loaded cpu
     if settings.cpu_affinity.enabled {
-        let mut cpu_loads = cpu::cpus_load()?;
-
-        cpu_loads.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-        let mut cpu_idx = 0;
-        for (idx, _) in cpu_loads.iter() {
-            // Note: shouldn't pin to core 0 since it is heavily used by the kernel for OS stuff
-            if cpu::cpu_core_id(*idx)? > 0 {
-                cpu_idx = *idx;
-                break;
+        thread.aff_mask = Some(cpu::get_aff_mask(tid)?);
+    }
+    Ok(thread)
+}
+
+fn optimize_process(
+    paths: &SystemPaths,
+    pid: nix::unistd::Pid,
+    settings: &cfg::Settings,
+    pstate: &mut ProcessState,
+    cpu_loads: Vec<(usize, f32)>,
+    avoid: &[usize], // Cores of the tree's games, for roles that are kept off them
+) -> Vec<gaiproto::KnobResult> {
+    tracing::info!("Optimizing process: {}", pid.as_raw());
+
+    // Knobs are independent, one failing should not stop the others. Each is applied to
+    // all threads or, after putting back the ones already changed, to none.
+    let mut results = Vec::new();
+    let boosted = pstate.role.is_boosted();
+    let threads = &pstate.threads;
+    if settings.niceness.enabled && boosted {
+        let applied = apply_to_threads(
+            paths,
+            pid,
+            |tid| scheduler::set_thread_niceness(tid, settings.niceness.optimized_value),
+            |tid| restore_niceness(tid, threads.get(&tid), settings),
+        );
+        results.push(knob_result(gaiproto::Knob::Niceness, applied));
+    }
+    if settings.ioniceness.enabled && boosted {
+        let applied = apply_to_threads(
+            paths,
+            pid,
+            |tid| io::set_thread_io_niceness(tid, settings.ioniceness.optimized_value),
+            |tid| restore_ioprio(tid, threads.get(&tid), settings),
+        );
+        results.push(knob_result(gaiproto::Knob::IoNiceness, applied));
+    }
+    let default_mask = settings
+        .cpu_affinity
+        .enabled
+        .then(get_aff_default)
+        .and_then(Result::ok);
+    let restore_mask = |tid| restore_affinity(tid, threads.get(&tid), default_mask);
+    let mut pinned_cpu = None;
+    if settings.cpu_affinity.enabled && pstate.role.is_pinned() {
+        // The main thread gets the core, the other threads everything else
+        let pinned = least_loaded_cpu(paths, cpu_loads).and_then(|cpu| {
+            let pin = |tid| match tid == pid {
+                true => cpu::pin_process(tid, cpu),
+                false => cpu::pin_process_excluding(tid, cpu),
+            };
+            apply_to_threads(paths, pid, pin, restore_mask)?;
+            Ok(cpu)
+        });
+        pinned_cpu = pinned.as_ref().ok().copied();
+        results.push(knob_result(gaiproto::Knob::CpuAffinity, pinned.map(|_| ())));
+    } else if settings.cpu_affinity.enabled && !avoid.is_empty() {
+        let applied = apply_to_threads(
+            paths,
+            pid,
+            |tid| cpu::exclude_cpus(tid, avoid),
+            restore_mask,
+        );
+        results.push(knob_result(gaiproto::Knob::CpuAffinity, applied));
+    }
+    if pinned_cpu.is_some() {
+        pstate.pinned_cpu = pinned_cpu;
+    }
+    results
+}
+
+// Applies one knob thread by thread as a transaction. Threads that exit meanwhile are
+// skipped, any other failure puts the threads already changed back with `undo`.
+fn apply_to_threads(
+    paths: &SystemPaths,
+    pid: nix::unistd::Pid,
+    apply: impl Fn(nix::unistd::Pid) -> anyhow::Result<()>,
+    undo: impl Fn(nix::unistd::Pid) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut tx = Transaction::new();
+    for tid in utils::get_process_tasks(paths, pid)? {
+        let tid = nix::unistd::Pid::from_raw(tid as i32);
+        let undo = &undo;
+        if let Err(why) = tx.step(format!("thread {}", tid), || apply(tid), move || undo(tid)) {
+            if tid != pid && !paths.tasks(pid).join(tid.to_string()).exists() {
+                continue;
             }
+            return Err(tx.rollback(why).error);
         }
+    }
+    tx.commit();
+    Ok(())
+}
+
+// An explicit request wins, then a profile for the executable, then the one it inherited
+fn select_profile(
+    paths: &SystemPaths,
+    pid: nix::unistd::Pid,
+    profiles: &Profiles,
+    requested: Option<&str>,
+    inherited: Option<profile::Selected>,
+) -> profile::Selected {
+    if let Some(selected) = requested.and_then(|name| profiles.get(name)) {
+        return selected;
+    }
+    std::fs::read_link(paths.process(pid).join("exe"))
+        .ok()
+        .and_then(|exe| profiles.matching(&exe.to_string_lossy()))
+        .or(inherited)
+        .unwrap_or_else(|| profiles.fallback())
+}
 
-        cpu::pin_process(pid, cpu_idx)?;
-        let tasks = &utils::get_process_tasks(pid)?[1..]; // 0 task is the process itself (main thread)
-        for task in tasks {
-            cpu::pin_process_excluding(nix::unistd::Pid::from_raw(*task as i32), cpu_idx)?;
+// Role under Steam or Wine, and the Wine prefix it runs in
+fn classify_process(
+    paths: &SystemPaths,
+    pid: nix::unistd::Pid,
+    settings: &cfg::Settings,
+) -> (steam::Role, Option<String>) {
+    if !settings.steam.enabled {
+        return (steam::Role::Other, None);
+    }
+    match steam::ProcessView::read(paths, pid) {
+        Ok(view) => (
+            steam::classify(&view),
+            view.wine_prefix().map(str::to_owned),
+        ),
+        Err(why) => {
+            tracing::debug!("Could not classify process {}: {}", pid, why);
+            (steam::Role::Other, None)
         }
     }
+}
 
+// Keeps every thread of the process off `cpus`
+fn keep_off(paths: &SystemPaths, pid: nix::unistd::Pid, cpus: &[usize]) -> anyhow::Result<()> {
+    for tid in utils::get_process_tasks(paths, pid)? {
+        cpu::exclude_cpus(nix::unistd::Pid::from_raw(tid as i32), cpus)?;
+    }
     Ok(())
 }
 
+// Applies the enabled knobs to one thread started after its process was optimized,
+// `avoid` are the cores it must stay off
+fn optimize_thread(
+    tid: nix::unistd::Pid,
+    settings: &cfg::Settings,
+    boosted: bool,
+    avoid: &[usize],
+) {
+    if settings.niceness.enabled
+        && boosted
+        && let Err(why) = scheduler::set_thread_niceness(tid, settings.niceness.optimized_value)
+    {
+        tracing::error!("Failed to apply niceness to thread {}: {}", tid, why);
+    }
+    if settings.ioniceness.enabled
+        && boosted
+        && let Err(why) = io::set_thread_io_niceness(tid, settings.ioniceness.optimized_value)
+    {
+        tracing::error!("Failed to apply I/O niceness to thread {}: {}", tid, why);
+    }
+    if settings.cpu_affinity.enabled
+        && !avoid.is_empty()
+        && let Err(why) = cpu::exclude_cpus(tid, avoid)
+    {
+        tracing::error!("Failed to apply affinity to thread {}: {}", tid, why);
+    }
+}
+
+// Find the lowest loaded cpu
+fn least_loaded_cpu(
+    paths: &SystemPaths,
+    mut cpu_loads: Vec<(usize, f32)>,
+) -> anyhow::Result<usize> {
+    cpu_loads.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let mut cpu_idx = 0;
+    for (idx, _) in cpu_loads.iter() {
+        // Note: shouldn't pin to core 0 since it is heavily used by the kernel for OS stuff
+        if cpu::cpu_core_id(paths, *idx)? > 0 {
+            cpu_idx = *idx;
+            break;
+        }
+    }
+
+    Ok(cpu_idx)
+}
+
+fn knob_result(knob: gaiproto::Knob, result: anyhow::Result<()>) -> gaiproto::KnobResult {
+    if let Err(why) = &result {
+        tracing::error!("Failed to apply {}: {}", knob.name(), why);
+    }
+    gaiproto::KnobResult {
+        knob,
+        error: result.err().map(|why| why.to_string()),
+    }
+}
+
+// All knobs applied -> Ok, none applied -> Error, otherwise Partial
+fn knobs_response(results: Vec<gaiproto::KnobResult>) -> gaiproto::Response {
+    if results.iter().all(|r| r.error.is_none()) {
+        return gaiproto::Response::Ok(results.into_iter().map(|r| r.knob).collect());
+    }
+    if results.iter().all(|r| r.error.is_some()) {
+        let message = results
+            .iter()
+            .map(|r| {
+                format!(
+                    "{}: {}",
+                    r.knob.name(),
+                    r.error.as_deref().unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        return gaiproto::Response::Error {
+            code: gaiproto::E_OPTIMIZE_FAILED,
+            message,
+        };
+    }
+    gaiproto::Response::Partial(results)
+}
+
 fn get_aff_default() -> anyhow::Result<libc::cpu_set_t> {
     let mut mask: libc::cpu_set_t = unsafe { std::mem::zeroed() };
     let cpus_n = cpu::cpus_num()?;
@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+
+// Where sysfs and procfs are mounted. Tests point these at fake trees under a temp dir.
+#[derive(Debug, Clone)]
+pub struct SystemPaths {
+    pub sys_root: PathBuf,
+    pub proc_root: PathBuf,
+}
+
+impl Default for SystemPaths {
+    fn default() -> Self {
+        Self {
+            sys_root: PathBuf::from("/sys"),
+            proc_root: PathBuf::from("/proc"),
+        }
+    }
+}
+
+impl SystemPaths {
+    pub fn new(sys_root: impl Into<PathBuf>, proc_root: impl Into<PathBuf>) -> Self {
+        Self {
+            sys_root: sys_root.into(),
+            proc_root: proc_root.into(),
+        }
+    }
+
+    fn cpu_dir(&self) -> PathBuf {
+        self.sys_root.join("devices/system/cpu")
+    }
+
+    // Glob matching `file` in every cpufreq policy, the root is escaped so it can't add wildcards
+    pub fn cpufreq_glob(&self, file: &str) -> String {
+        let root = glob::Pattern::escape(&self.cpu_dir().join("cpufreq").to_string_lossy());
+        format!("{}/policy*/{}", root, file)
+    }
+
+    pub fn core_id(&self, cpu: usize) -> PathBuf {
+        self.cpu_dir()
+            .join(format!("cpu{}", cpu))
+            .join("topology/core_id")
+    }
+
+    pub fn stat(&self) -> PathBuf {
+        self.proc_root.join("stat")
+    }
+
+    pub fn process(&self, pid: nix::unistd::Pid) -> PathBuf {
+        self.proc_root.join(pid.as_raw().to_string())
+    }
+
+    pub fn tasks(&self, pid: nix::unistd::Pid) -> PathBuf {
+        self.process(pid).join("task")
+    }
+
+    // Children started by one thread, space separated
+    pub fn children(&self, pid: nix::unistd::Pid, tid: u32) -> PathBuf {
+        self.tasks(pid).join(tid.to_string()).join("children")
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.sys_root == Path::new("/sys") && self.proc_root == Path::new("/proc")
+    }
+}
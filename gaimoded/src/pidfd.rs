@@ -0,0 +1,41 @@
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+
+pub fn open(pid: nix::unistd::Pid) -> anyhow::Result<OwnedFd> {
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if ret < 0 {
+        return Err(anyhow::anyhow!(
+            "Could not open pidfd for {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as i32) })
+}
+
+// Process the pidfd refers to, None once it has exited
+pub fn pid_of(fd: &OwnedFd) -> anyhow::Result<Option<nix::unistd::Pid>> {
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd()))?;
+    let pid = fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Pid:"))
+        .ok_or_else(|| anyhow::anyhow!("File descriptor is not a pidfd"))?
+        .trim()
+        .parse::<i32>()?;
+    // Kernel reports -1 for a process that is gone
+    Ok((pid > 0).then(|| nix::unistd::Pid::from_raw(pid)))
+}
+
+// A pidfd becomes readable when its process exits
+pub fn has_exited(fd: &OwnedFd) -> bool {
+    let mut fds = [nix::poll::PollFd::new(
+        fd.as_fd(),
+        nix::poll::PollFlags::POLLIN,
+    )];
+    match nix::poll::poll(&mut fds, nix::poll::PollTimeout::ZERO) {
+        Ok(n) => n > 0,
+        Err(why) => {
+            tracing::error!("Could not poll pidfd: {}", why);
+            false
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use nix::unistd::Pid;
+use tokio::{
+    io::{Interest, unix::AsyncFd},
+    sync::mpsc::UnboundedSender,
+};
+
+use crate::utils;
+
+// From linux/connector.h and linux/cn_proc.h
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_EVENT_FORK: u32 = 0x0000_0001;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+const NLMSG_HDRLEN: usize = 16;
+const CN_MSG_HDRLEN: usize = 20;
+// what, cpu and timestamp come before the per-event data
+const PROC_EVENT_HDRLEN: usize = 16;
+const READ_CHUNK: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcEvent {
+    // A new process when `pid == tgid`, otherwise a new thread of process `tgid`
+    Fork { parent: Pid, pid: Pid, tgid: Pid },
+    // Only the thread that called exec is left, and it now has the process' PID
+    Exec { pid: Pid },
+    // Split like Fork, `pid == tgid` is the main thread
+    Exit { pid: Pid, tgid: Pid },
+}
+
+// Kernel process events from the netlink proc connector, needs CAP_NET_ADMIN
+pub struct ProcConnector {
+    fd: AsyncFd<OwnedFd>,
+    buf: [u8; READ_CHUNK],
+}
+
+impl ProcConnector {
+    pub fn open() -> anyhow::Result<ProcConnector> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(anyhow::anyhow!(
+                "Could not open netlink socket: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // The kernel picks the port id
+        let addr = nix::sys::socket::NetlinkAddr::new(0, CN_IDX_PROC);
+        nix::sys::socket::bind(fd.as_raw_fd(), &addr)?;
+
+        let listen = listen_message();
+        let sent =
+            nix::sys::socket::send(fd.as_raw_fd(), &listen, nix::sys::socket::MsgFlags::empty())?;
+        if sent != listen.len() {
+            return Err(anyhow::anyhow!("Short write subscribing to process events"));
+        }
+
+        Ok(ProcConnector {
+            fd: AsyncFd::new(fd)?,
+            buf: [0u8; READ_CHUNK],
+        })
+    }
+
+    // Waits for the next datagram. ENOBUFS means the kernel dropped events.
+    pub async fn next_events(&mut self) -> std::io::Result<Vec<ProcEvent>> {
+        let buf = &mut self.buf;
+        let (n, sender) = self
+            .fd
+            .async_io(Interest::READABLE, |fd| {
+                nix::sys::socket::recvfrom::<nix::sys::socket::NetlinkAddr>(fd.as_raw_fd(), buf)
+                    .map_err(std::io::Error::from)
+            })
+            .await?;
+        // Any local process can send to the socket, only the kernel's port 0 is believed
+        if sender.is_none_or(|addr| addr.pid() != 0) {
+            tracing::warn!(
+                "Dropped process events not sent by the kernel: {:?}",
+                sender
+            );
+            return Ok(Vec::new());
+        }
+        Ok(parse(&buf[..n]))
+    }
+}
+
+// Passes events to the optimizer until it goes away
+pub async fn forward(mut connector: ProcConnector, tx: UnboundedSender<utils::Commands>) {
+    loop {
+        let events = match connector.next_events().await {
+            Ok(events) => events,
+            Err(why) if why.raw_os_error() == Some(libc::ENOBUFS) => {
+                // Periodic liveness checks and scans make up for what was lost
+                tracing::warn!("Process events came in too fast, some were dropped");
+                continue;
+            }
+            Err(why) => {
+                tracing::error!("Process events stopped: {}", why);
+                return;
+            }
+        };
+        for event in events {
+            if tx.send(utils::Commands::ProcEvent(event)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// nlmsghdr, cn_msg and the PROC_CN_MCAST_LISTEN op
+fn listen_message() -> Vec<u8> {
+    let op = PROC_CN_MCAST_LISTEN.to_ne_bytes();
+    let len = NLMSG_HDRLEN + CN_MSG_HDRLEN + op.len();
+
+    let mut msg = Vec::with_capacity(len);
+    msg.extend((len as u32).to_ne_bytes());
+    msg.extend((libc::NLMSG_DONE as u16).to_ne_bytes());
+    msg.extend(0u16.to_ne_bytes()); // flags
+    msg.extend(0u32.to_ne_bytes()); // seq
+    msg.extend(std::process::id().to_ne_bytes());
+
+    msg.extend(CN_IDX_PROC.to_ne_bytes());
+    msg.extend(CN_VAL_PROC.to_ne_bytes());
+    msg.extend(0u32.to_ne_bytes()); // seq
+    msg.extend(0u32.to_ne_bytes()); // ack
+    msg.extend((op.len() as u16).to_ne_bytes());
+    msg.extend(0u16.to_ne_bytes()); // flags
+    msg.extend(op);
+    msg
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+fn pid_at(buf: &[u8], offset: usize) -> Option<Pid> {
+    u32_at(buf, offset).map(|pid| Pid::from_raw(pid as i32))
+}
+
+// Events in one datagram, anything we don't follow or can't read is skipped
+fn parse(datagram: &[u8]) -> Vec<ProcEvent> {
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while let Some(len) = u32_at(datagram, offset) {
+        let len = len as usize;
+        if len < NLMSG_HDRLEN || offset + len > datagram.len() {
+            break;
+        }
+        if let Some(event) = parse_cn_msg(&datagram[offset + NLMSG_HDRLEN..offset + len]) {
+            events.push(event);
+        }
+        // Messages are 4 byte aligned
+        offset += (len + 3) & !3;
+    }
+    events
+}
+
+fn parse_cn_msg(msg: &[u8]) -> Option<ProcEvent> {
+    if u32_at(msg, 0)? != CN_IDX_PROC || u32_at(msg, 4)? != CN_VAL_PROC {
+        return None;
+    }
+    let event = msg.get(CN_MSG_HDRLEN..)?;
+    let data = PROC_EVENT_HDRLEN;
+    match u32_at(event, 0)? {
+        PROC_EVENT_FORK => Some(ProcEvent::Fork {
+            // parent_pid comes first, the process it belongs to is parent_tgid
+            parent: pid_at(event, data + 4)?,
+            pid: pid_at(event, data + 8)?,
+            tgid: pid_at(event, data + 12)?,
+        }),
+        PROC_EVENT_EXEC => Some(ProcEvent::Exec {
+            pid: pid_at(event, data + 4)?,
+        }),
+        PROC_EVENT_EXIT => Some(ProcEvent::Exit {
+            pid: pid_at(event, data)?,
+            tgid: pid_at(event, data + 4)?,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(what: u32, data: &[u32]) -> Vec<u8> {
+        let mut event = Vec::new();
+        event.extend(what.to_ne_bytes());
+        event.extend(0u32.to_ne_bytes()); // cpu
+        event.extend(0u64.to_ne_bytes()); // timestamp
+        for value in data {
+            event.extend(value.to_ne_bytes());
+        }
+
+        let len = NLMSG_HDRLEN + CN_MSG_HDRLEN + event.len();
+        let mut msg = Vec::new();
+        msg.extend((len as u32).to_ne_bytes());
+        msg.extend([0u8; NLMSG_HDRLEN - 4]);
+        msg.extend(CN_IDX_PROC.to_ne_bytes());
+        msg.extend(CN_VAL_PROC.to_ne_bytes());
+        msg.extend([0u8; 8]); // seq, ack
+        msg.extend((event.len() as u16).to_ne_bytes());
+        msg.extend(0u16.to_ne_bytes());
+        msg.extend(event);
+        msg
+    }
+
+    #[test]
+    fn fork_of_process_and_thread() {
+        let process = datagram(PROC_EVENT_FORK, &[10, 10, 20, 20]);
+        assert_eq!(
+            parse(&process),
+            vec![ProcEvent::Fork {
+                parent: Pid::from_raw(10),
+                pid: Pid::from_raw(20),
+                tgid: Pid::from_raw(20),
+            }]
+        );
+        let thread = datagram(PROC_EVENT_FORK, &[11, 10, 21, 10]);
+        assert_eq!(
+            parse(&thread),
+            vec![ProcEvent::Fork {
+                parent: Pid::from_raw(10),
+                pid: Pid::from_raw(21),
+                tgid: Pid::from_raw(10),
+            }]
+        );
+    }
+
+    #[test]
+    fn exec_and_exit() {
+        let exec = datagram(PROC_EVENT_EXEC, &[30, 30]);
+        assert_eq!(
+            parse(&exec),
+            vec![ProcEvent::Exec {
+                pid: Pid::from_raw(30)
+            }]
+        );
+        // exit_code, exit_signal and the parent follow, they are not needed
+        let exit = datagram(PROC_EVENT_EXIT, &[31, 30, 0, 17, 1, 1]);
+        assert_eq!(
+            parse(&exit),
+            vec![ProcEvent::Exit {
+                pid: Pid::from_raw(31),
+                tgid: Pid::from_raw(30),
+            }]
+        );
+    }
+
+    #[test]
+    fn skips_unfollowed_and_truncated() {
+        // PROC_EVENT_UID
+        assert!(parse(&datagram(0x4, &[1, 1, 0, 0])).is_empty());
+        let mut fork = datagram(PROC_EVENT_FORK, &[10, 10, 20, 20]);
+        fork.truncate(fork.len() - 2);
+        assert!(parse(&fork).is_empty());
+        assert!(parse(&[1, 2, 3]).is_empty());
+    }
+
+    #[test]
+    fn several_messages_in_one_datagram() {
+        let mut both = datagram(PROC_EVENT_EXEC, &[30, 30]);
+        both.extend(datagram(PROC_EVENT_EXIT, &[30, 30, 0, 17, 1, 1]));
+        assert_eq!(parse(&both).len(), 2);
+    }
+}
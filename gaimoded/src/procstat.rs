@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+
+// Per-CPU counters from a `cpuN` line of /proc/stat, in USER_HZ ticks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTimes {
+    pub user: u64,
+    pub nice: u64,
+    pub system: u64,
+    pub idle: u64,
+    pub iowait: u64,
+    pub irq: u64,
+    pub softirq: u64,
+    pub steal: u64,
+    pub guest: u64,
+    pub guest_nice: u64,
+}
+
+impl CpuTimes {
+    pub fn idle_total(&self) -> u64 {
+        self.idle + self.iowait
+    }
+
+    // Guest time is already part of user and nice, adding it would count it twice
+    pub fn total(&self) -> u64 {
+        self.user
+            + self.nice
+            + self.system
+            + self.idle
+            + self.iowait
+            + self.irq
+            + self.softirq
+            + self.steal
+    }
+
+    // Busy percentage between an earlier snapshot and this one
+    pub fn load_since(&self, earlier: &CpuTimes) -> f32 {
+        // Counters can go backwards when a CPU is hotplugged, treat that as no time passed
+        let total_d = self.total().saturating_sub(earlier.total());
+        let idle_d = self.idle_total().saturating_sub(earlier.idle_total());
+        if total_d == 0 {
+            return 0.0;
+        }
+        (total_d.saturating_sub(idle_d) as f32 / total_d as f32) * 100.0f32
+    }
+}
+
+// Counters of every CPU listed, keyed by the number in its `cpuN` label.
+// Offline CPUs have no line, so the keys may have gaps.
+pub fn parse(stat: &str) -> anyhow::Result<BTreeMap<usize, CpuTimes>> {
+    let mut cpus = BTreeMap::new();
+    for line in stat.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(label) = fields.next() else {
+            continue;
+        };
+        // The aggregate "cpu" line and non-CPU lines (intr, ctxt, ...) are skipped
+        let Some(id) = label.strip_prefix("cpu").filter(|id| !id.is_empty()) else {
+            continue;
+        };
+        let id = id
+            .parse::<usize>()
+            .map_err(|_| anyhow::anyhow!("Bad CPU label '{}' in /proc/stat", label))?;
+
+        let values = fields
+            .map(|value| value.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|why| anyhow::anyhow!("Bad counter for {}: {}", label, why))?;
+        // user, nice, system and idle are always there, the rest came with later kernels
+        if values.len() < 4 {
+            return Err(anyhow::anyhow!(
+                "{} has {} counters, expected at least 4",
+                label,
+                values.len()
+            ));
+        }
+        let field = |n: usize| values.get(n).copied().unwrap_or(0);
+        cpus.insert(
+            id,
+            CpuTimes {
+                user: field(0),
+                nice: field(1),
+                system: field(2),
+                idle: field(3),
+                iowait: field(4),
+                irq: field(5),
+                softirq: field(6),
+                steal: field(7),
+                guest: field(8),
+                guest_nice: field(9),
+            },
+        );
+    }
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Captured on a 4 thread machine with cpu2 offline
+    const SAMPLE_START: &str = "\
+cpu  10132153 290696 3084719 46828483 16683 0 25195 0 175628 0
+cpu0 1393280 32966 572056 13343292 6130 0 17875 0 23276 0
+cpu1 1335950 12612 334470 13573962 3373 0 3064 0 21108 0
+cpu3 1209612 17839 288327 13669386 2733 0 1012 0 23103 0
+intr 199292311 24 0 0 0 0 0 0 0 1 0 0 0 0 0 0 0 0 0 0 0 0 0
+ctxt 402837165
+btime 1700000000
+processes 1345323
+procs_running 2
+procs_blocked 0
+softirq 48202153 2 11293012 4 1212823 0 0 65842 17453222 0 18177248
+";
+
+    const SAMPLE_END: &str = "\
+cpu  10132353 290696 3084819 46828983 16683 0 25195 100 175628 0
+cpu0 1393380 32966 572106 13343342 6130 0 17875 0 23276 0
+cpu1 1335950 12612 334470 13574062 3373 0 3064 0 21108 0
+cpu3 1209662 17839 288377 13669386 2733 0 1012 100 23103 0
+intr 199292411 24 0 0 0 0 0 0 0 1 0 0 0 0 0 0 0 0 0 0 0 0 0
+";
+
+    #[test]
+    fn keys_by_real_cpu_number() {
+        let cpus = parse(SAMPLE_START).unwrap();
+        assert_eq!(cpus.keys().copied().collect::<Vec<_>>(), vec![0, 1, 3]);
+        assert_eq!(cpus[&3].user, 1209612);
+        assert_eq!(cpus[&0].guest, 23276);
+    }
+
+    #[test]
+    fn counters_past_u32() {
+        let stat = "cpu0 5000000000 0 0 9000000000 0 0 0 0 0 0\n";
+        let cpus = parse(stat).unwrap();
+        assert_eq!(cpus[&0].user, 5_000_000_000);
+        assert_eq!(cpus[&0].idle, 9_000_000_000);
+    }
+
+    #[test]
+    fn reads_steal_and_guest() {
+        let stat = "cpu7 1 2 3 4 5 6 7 8 9 10\n";
+        let cpus = parse(stat).unwrap();
+        assert_eq!(
+            cpus[&7],
+            CpuTimes {
+                user: 1,
+                nice: 2,
+                system: 3,
+                idle: 4,
+                iowait: 5,
+                irq: 6,
+                softirq: 7,
+                steal: 8,
+                guest: 9,
+                guest_nice: 10,
+            }
+        );
+    }
+
+    #[test]
+    fn old_kernels_with_four_fields() {
+        let cpus = parse("cpu0 10 20 30 40\n").unwrap();
+        assert_eq!(cpus[&0].idle, 40);
+        assert_eq!(cpus[&0].steal, 0);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(parse("cpu0 10 20\n").is_err());
+        assert!(parse("cpu0 10 20 x 40\n").is_err());
+        assert!(parse("cpuX 10 20 30 40\n").is_err());
+    }
+
+    #[test]
+    fn load_between_samples() {
+        let start = parse(SAMPLE_START).unwrap();
+        let end = parse(SAMPLE_END).unwrap();
+        // 150 busy out of 200 ticks
+        assert_eq!(end[&0].load_since(&start[&0]), 75.0);
+        // Only idle time passed
+        assert_eq!(end[&1].load_since(&start[&1]), 0.0);
+        // Steal counts as busy, the hypervisor took the CPU away
+        assert_eq!(end[&3].load_since(&start[&3]), 100.0);
+    }
+
+    #[test]
+    fn counters_going_backwards() {
+        let start = parse("cpu0 100 0 100 100\n").unwrap();
+        let end = parse("cpu0 10 0 10 10\n").unwrap();
+        assert_eq!(end[&0].load_since(&start[&0]), 0.0);
+    }
+}
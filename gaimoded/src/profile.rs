@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use glob::Pattern;
+
+use crate::cfg;
+
+// Used when no profile is asked for or matched
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Settings one process is optimized with, `name` is None for the top level ones
+#[derive(Clone, Default)]
+pub struct Selected {
+    pub name: Option<String>,
+    pub settings: Arc<cfg::Settings>,
+}
+
+struct Profile {
+    name: String,
+    match_exe: Vec<Pattern>,
+    settings: Arc<cfg::Settings>,
+}
+
+impl Profile {
+    fn matches(&self, exe: &str) -> bool {
+        let file_name = exe.rsplit('/').next().unwrap_or_default();
+        self.match_exe
+            .iter()
+            .any(|pattern| pattern.matches(exe) || pattern.matches(file_name))
+    }
+
+    fn selected(&self) -> Selected {
+        Selected {
+            name: Some(self.name.clone()),
+            settings: self.settings.clone(),
+        }
+    }
+}
+
+// Profiles from the settings, resolved against the top level sections
+pub struct Profiles {
+    base: Arc<cfg::Settings>,
+    // Sorted by name, so the first match does not depend on the settings file order
+    named: Vec<Profile>,
+}
+
+impl Profiles {
+    pub fn compile(settings: &cfg::Settings) -> anyhow::Result<Profiles> {
+        let mut named = settings
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                let match_exe = profile
+                    .match_exe
+                    .iter()
+                    .map(|pattern| Pattern::new(pattern))
+                    .collect::<Result<_, _>>()
+                    .map_err(|why| anyhow::anyhow!("Profile '{}': {}", name, why))?;
+                Ok(Profile {
+                    name: name.clone(),
+                    match_exe,
+                    settings: Arc::new(settings.with_profile(profile)),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        named.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Profiles {
+            named,
+            ..Profiles::base_only(settings)
+        })
+    }
+
+    // Every process gets the top level settings
+    pub fn base_only(settings: &cfg::Settings) -> Profiles {
+        let mut base = settings.clone();
+        base.profiles.clear();
+        Profiles {
+            base: Arc::new(base),
+            named: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Selected> {
+        self.named
+            .iter()
+            .find(|profile| profile.name == name)
+            .map(Profile::selected)
+    }
+
+    // The first profile with a pattern matching `exe`
+    pub fn matching(&self, exe: &str) -> Option<Selected> {
+        self.named
+            .iter()
+            .find(|profile| profile.matches(exe))
+            .map(Profile::selected)
+    }
+
+    // The `default` profile if there is one, the top level settings otherwise
+    pub fn fallback(&self) -> Selected {
+        self.get(DEFAULT_PROFILE).unwrap_or_else(|| Selected {
+            name: None,
+            settings: self.base.clone(),
+        })
+    }
+
+    // The top level settings and those of every profile
+    pub fn all_settings(&self) -> impl Iterator<Item = &cfg::Settings> {
+        std::iter::once(&*self.base).chain(self.named.iter().map(|profile| &*profile.settings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(profiles: &[(&str, &[&str], i32)]) -> cfg::Settings {
+        let mut settings = cfg::Settings::default();
+        for (name, patterns, niceness) in profiles {
+            let mut section = settings.niceness.clone();
+            section.optimized_value = *niceness;
+            settings.profiles.insert(
+                name.to_string(),
+                cfg::Profile {
+                    match_exe: patterns.iter().map(|p| p.to_string()).collect(),
+                    cpu_affinity: None,
+                    cpu_governor: None,
+                    niceness: Some(section),
+                    ioniceness: None,
+                },
+            );
+        }
+        settings
+    }
+
+    #[test]
+    fn matches_path_or_file_name() {
+        let profiles = Profiles::compile(&settings(&[
+            ("cs2", &["cs2"], -15),
+            ("steam", &["*/steamapps/common/*"], -5),
+        ]))
+        .unwrap();
+
+        let cs2 = profiles
+            .matching("/games/steamapps/common/Counter-Strike/cs2")
+            .unwrap();
+        assert_eq!(cs2.name.as_deref(), Some("cs2"));
+        assert_eq!(cs2.settings.niceness.optimized_value, -15);
+        // Sections the profile leaves out come from the top level
+        assert_eq!(
+            cs2.settings.ioniceness.optimized_value,
+            cfg::Settings::default().ioniceness.optimized_value
+        );
+
+        let other = profiles.matching("/games/steamapps/common/Portal/portal2_linux");
+        assert_eq!(other.unwrap().name.as_deref(), Some("steam"));
+        assert!(profiles.matching("/usr/bin/bash").is_none());
+    }
+
+    #[test]
+    fn falls_back_to_default_profile() {
+        let base = Profiles::compile(&settings(&[("cs2", &["cs2"], -15)])).unwrap();
+        assert!(base.fallback().name.is_none());
+
+        let with_default =
+            Profiles::compile(&settings(&[("cs2", &["cs2"], -15), ("default", &[], -3)])).unwrap();
+        let fallback = with_default.fallback();
+        assert_eq!(fallback.name.as_deref(), Some("default"));
+        assert_eq!(fallback.settings.niceness.optimized_value, -3);
+    }
+
+    #[test]
+    fn rejects_invalid_pattern() {
+        assert!(Profiles::compile(&settings(&[("broken", &["[cs2"], 0)])).is_err());
+    }
+}
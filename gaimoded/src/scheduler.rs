@@ -20,21 +20,11 @@ pub fn process_niceness(pid: nix::unistd::Pid) -> anyhow::Result<i32> {
     }
 }
 
-// Optimizes scheduling of a process
-pub fn set_process_niceness(pid: nix::unistd::Pid, niceness: i32) -> anyhow::Result<()> {
-    unsafe {
-        // Task contains process itself
-        let tasks_path = format!("/proc/{}/task/", pid.as_raw());
-        let dir_iter = std::fs::read_dir(&tasks_path)?;
-
-        for task in dir_iter {
-            let task_tid = task?.file_name().to_string_lossy().parse::<u32>()?;
-
-            let ret = libc::setpriority(libc::PRIO_PROCESS, task_tid, niceness);
-            if ret < 0 {
-                return Err(anyhow::anyhow!("Could not setpriority"));
-            }
-        }
-        Ok(())
+// Linux keeps niceness per thread, so a TID can be passed as well
+pub fn set_thread_niceness(tid: nix::unistd::Pid, niceness: i32) -> anyhow::Result<()> {
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, tid.as_raw() as u32, niceness) };
+    if ret < 0 {
+        return Err(anyhow::anyhow!("Could not set niceness of thread {}", tid));
     }
+    Ok(())
 }
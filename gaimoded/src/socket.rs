@@ -0,0 +1,88 @@
+use std::{
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::utils;
+
+// First descriptor systemd hands over, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: i32 = 3;
+
+pub struct Socket {
+    pub listener: tokio::net::UnixListener,
+    // Set when we created the socket file and have to remove it on exit
+    pub owned_path: Option<PathBuf>,
+}
+
+// Takes the socket from systemd if it passed one, otherwise binds `path`
+pub fn open(path: &Path) -> anyhow::Result<Socket> {
+    if let Some(listener) = from_systemd()? {
+        tracing::info!("Using socket passed by systemd");
+        return Ok(Socket {
+            listener,
+            owned_path: None,
+        });
+    }
+    bind(path)
+}
+
+fn from_systemd() -> anyhow::Result<Option<tokio::net::UnixListener>> {
+    let Ok(listen_pid) = std::env::var("LISTEN_PID") else {
+        return Ok(None);
+    };
+    // Inherited from whoever started us, the sockets aren't ours
+    if listen_pid.parse::<u32>()? != std::process::id() {
+        return Ok(None);
+    }
+    let fds = std::env::var("LISTEN_FDS")?.parse::<i32>()?;
+    if fds != 1 {
+        return Err(anyhow::anyhow!(
+            "Expected one socket from systemd, got {}",
+            fds
+        ));
+    }
+
+    use std::os::fd::FromRawFd;
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(tokio::net::UnixListener::from_std(listener)?))
+}
+
+fn bind(path: &Path) -> anyhow::Result<Socket> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    // A socket left by a crashed daemon can go, anything else stays where it is
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow::anyhow!(
+                "{} exists and is not a socket",
+                path.display()
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow::anyhow!(
+                "Another daemon is already listening on {}",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    // Only root and the gaimode group may talk to the daemon
+    match nix::unistd::Group::from_name(utils::UDS_GROUP) {
+        Ok(Some(group)) => nix::unistd::chown(path, None, Some(group.gid))?,
+        _ => tracing::warn!(
+            "Group '{}' does not exist, only root can use the socket",
+            utils::UDS_GROUP
+        ),
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o660))?;
+
+    Ok(Socket {
+        listener,
+        owned_path: Some(path.to_owned()),
+    })
+}
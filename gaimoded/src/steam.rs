@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+
+use nix::unistd::Pid;
+
+use crate::{auth, paths::SystemPaths, utils};
+
+// Set by Steam for everything it launches
+const STEAM_ENV: [&str; 4] = [
+    "SteamAppId",
+    "SteamGameId",
+    "STEAM_COMPAT_DATA_PATH",
+    "STEAM_COMPAT_CLIENT_INSTALL_PATH",
+];
+
+// Steam and Steam Linux Runtime launchers that sit between Steam and the game
+const WRAPPERS: [&str; 11] = [
+    "reaper",
+    "steam-launch-wrapper",
+    "pressure-vessel-wrap",
+    "pressure-vessel-adverb",
+    "pressure-vessel-launcher",
+    "pv-bwrap",
+    "srt-bwrap",
+    "_v2-entry-point",
+    "sh",
+    "bash",
+    "dash",
+];
+
+// Wine's own services run from C:\windows, the game never does
+const WINE_SYSTEM_DIR: &str = "\\windows\\";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Role {
+    // Gets the full treatment, its core is kept free of everything else in the tree
+    Game,
+    // Wine's server, every call into Wine goes through it
+    WineServer,
+    // Launchers and Wine services, only kept off the game's core
+    Wrapper,
+    // Not part of a Steam or Wine tree, treated like a game
+    #[default]
+    Other,
+}
+
+impl Role {
+    // Roles that get niceness and I/O priority
+    pub fn is_boosted(self) -> bool {
+        self != Role::Wrapper
+    }
+
+    // Roles that get a core of their own, the rest are kept off it
+    pub fn is_pinned(self) -> bool {
+        matches!(self, Role::Game | Role::Other)
+    }
+}
+
+// What classification looks at, read from procfs
+pub struct ProcessView {
+    pub comm: String,
+    pub exe: String,
+    pub argv: Vec<String>,
+    pub env: HashMap<String, String>,
+}
+
+impl ProcessView {
+    pub fn read(paths: &SystemPaths, pid: Pid) -> anyhow::Result<ProcessView> {
+        let dir = paths.process(pid);
+        let comm = std::fs::read_to_string(dir.join("comm"))?;
+        let exe = std::fs::read_link(dir.join("exe"))?;
+        let argv = nul_separated(&std::fs::read(dir.join("cmdline"))?);
+        // Unreadable for processes of other users, they are never ours to classify anyway
+        let env = nul_separated(&std::fs::read(dir.join("environ"))?)
+            .into_iter()
+            .filter_map(|var| {
+                let (key, value) = var.split_once('=')?;
+                Some((key.to_owned(), value.to_owned()))
+            })
+            .collect();
+        Ok(ProcessView {
+            comm: comm.trim_end().to_owned(),
+            exe: exe.to_string_lossy().into_owned(),
+            argv,
+            env,
+        })
+    }
+
+    fn exe_name(&self) -> &str {
+        self.exe.rsplit('/').next().unwrap_or_default()
+    }
+
+    fn is_steam(&self) -> bool {
+        STEAM_ENV.iter().any(|var| self.env.contains_key(*var))
+    }
+
+    // Windows program run by wine's preloader, argv[0] is its Windows path
+    fn windows_exe(&self) -> Option<&str> {
+        if !self.exe_name().starts_with("wine") {
+            return None;
+        }
+        let program = self.argv.first()?;
+        program
+            .to_ascii_lowercase()
+            .ends_with(".exe")
+            .then_some(program.as_str())
+    }
+
+    pub fn wine_prefix(&self) -> Option<&str> {
+        self.env.get("WINEPREFIX").map(String::as_str)
+    }
+}
+
+fn nul_separated(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+pub fn classify(view: &ProcessView) -> Role {
+    if view.comm == "wineserver" || view.exe_name() == "wineserver" {
+        return Role::WineServer;
+    }
+    if let Some(program) = view.windows_exe() {
+        if program.to_ascii_lowercase().contains(WINE_SYSTEM_DIR) {
+            return Role::Wrapper;
+        }
+        return Role::Game;
+    }
+    if !view.is_steam() {
+        return Role::Other;
+    }
+    let is_wrapper = WRAPPERS.contains(&view.comm.as_str())
+        || WRAPPERS.contains(&view.exe_name())
+        // Proton is a python script, comm only says python
+        || view.argv.iter().take(2).any(|arg| arg.ends_with("/proton"));
+    match is_wrapper {
+        true => Role::Wrapper,
+        false => Role::Game,
+    }
+}
+
+// The wineserver serving `prefix`. It detaches from whoever started it, so it is
+// usually not among the game's descendants. Only processes of `owner` are considered.
+pub fn find_wineserver(
+    paths: &SystemPaths,
+    prefix: &str,
+    owner: nix::unistd::Uid,
+) -> anyhow::Result<Option<Pid>> {
+    for pid in utils::list_processes(paths)? {
+        if !matches!(auth::process_owner(paths, pid), Ok(uid) if uid == owner) {
+            continue;
+        }
+        let Ok(view) = ProcessView::read(paths, pid) else {
+            continue;
+        };
+        if classify(&view) == Role::WineServer && view.wine_prefix() == Some(prefix) {
+            return Ok(Some(pid));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(comm: &str, exe: &str, argv: &[&str], env: &[(&str, &str)]) -> ProcessView {
+        ProcessView {
+            comm: comm.to_owned(),
+            exe: exe.to_owned(),
+            argv: argv.iter().map(|s| s.to_string()).collect(),
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    const STEAM: [(&str, &str); 1] = [("SteamAppId", "620")];
+
+    #[test]
+    fn proton_game_and_wine_services() {
+        let game = view(
+            "Game.exe",
+            "/opt/proton/files/bin/wine64-preloader",
+            &["Z:\\games\\Game\\Game.exe", "-dx12"],
+            &STEAM,
+        );
+        assert_eq!(classify(&game), Role::Game);
+
+        let services = view(
+            "services.exe",
+            "/opt/proton/files/bin/wine64-preloader",
+            &["C:\\windows\\system32\\services.exe"],
+            &STEAM,
+        );
+        assert_eq!(classify(&services), Role::Wrapper);
+
+        let server = view(
+            "wineserver",
+            "/opt/proton/files/bin/wineserver",
+            &["/opt/proton/files/bin/wineserver"],
+            &STEAM,
+        );
+        assert_eq!(classify(&server), Role::WineServer);
+    }
+
+    #[test]
+    fn steam_wrappers() {
+        let reaper = view(
+            "reaper",
+            "/home/u/.steam/steam/ubuntu12_32/reaper",
+            &["reaper", "SteamLaunch", "AppId=620"],
+            &STEAM,
+        );
+        assert_eq!(classify(&reaper), Role::Wrapper);
+
+        let proton = view(
+            "python3",
+            "/usr/bin/python3.12",
+            &["python3", "/opt/proton/proton", "waitforexitandrun"],
+            &STEAM,
+        );
+        assert_eq!(classify(&proton), Role::Wrapper);
+    }
+
+    #[test]
+    fn native_steam_game() {
+        let game = view(
+            "portal2_linux",
+            "/games/portal2_linux",
+            &["./portal2_linux"],
+            &STEAM,
+        );
+        assert_eq!(classify(&game), Role::Game);
+    }
+
+    #[test]
+    fn outside_steam() {
+        let shell = view("bash", "/usr/bin/bash", &["bash"], &[]);
+        assert_eq!(classify(&shell), Role::Other);
+        let lutris_game = view(
+            "Game.exe",
+            "/usr/bin/wine64-preloader",
+            &["C:\\Games\\Game.exe"],
+            &[("WINEPREFIX", "/home/u/Games/game")],
+        );
+        assert_eq!(classify(&lutris_game), Role::Game);
+        assert_eq!(lutris_game.wine_prefix(), Some("/home/u/Games/game"));
+    }
+}
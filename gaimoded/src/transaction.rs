@@ -0,0 +1,118 @@
+type Undo<'a> = Box<dyn FnOnce() -> anyhow::Result<()> + 'a>;
+
+// What a rolled back transaction reports
+pub struct RolledBack {
+    pub error: anyhow::Error,
+    // False when some steps could not be undone and stay changed
+    pub complete: bool,
+}
+
+// Changes applied one step at a time, so a failure part way can take back the steps before it
+#[derive(Default)]
+pub struct Transaction<'a> {
+    applied: Vec<(String, Undo<'a>)>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new() -> Transaction<'a> {
+        Transaction::default()
+    }
+
+    // Runs `apply`, `undo` is kept only if it succeeded
+    pub fn step(
+        &mut self,
+        what: impl Into<String>,
+        apply: impl FnOnce() -> anyhow::Result<()>,
+        undo: impl FnOnce() -> anyhow::Result<()> + 'a,
+    ) -> anyhow::Result<()> {
+        apply()?;
+        self.applied.push((what.into(), Box::new(undo)));
+        Ok(())
+    }
+
+    // Everything applied stays
+    pub fn commit(self) {}
+
+    // Undoes the applied steps, last first, and turns `why` into the error to report.
+    // Steps that can't be undone are named in it.
+    pub fn rollback(self, why: anyhow::Error) -> RolledBack {
+        let mut stuck = Vec::new();
+        for (what, undo) in self.applied.into_iter().rev() {
+            if let Err(undo_why) = undo() {
+                tracing::error!("Could not undo {}: {}", what, undo_why);
+                stuck.push(what);
+            }
+        }
+        let error = match stuck.is_empty() {
+            true => anyhow::anyhow!("{}, rolled back", why),
+            false => anyhow::anyhow!("{}, could not roll back {}", why, stuck.join(", ")),
+        };
+        RolledBack {
+            error,
+            complete: stuck.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn rollback_undoes_applied_steps_last_first() {
+        let log = RefCell::new(Vec::new());
+        let mut tx = Transaction::new();
+        for n in 0..3 {
+            let log = &log;
+            let apply = || {
+                log.borrow_mut().push(format!("apply {}", n));
+                Ok(())
+            };
+            let undo = move || {
+                log.borrow_mut().push(format!("undo {}", n));
+                Ok(())
+            };
+            tx.step(format!("step {}", n), apply, undo).unwrap();
+        }
+        let failed = tx.step("step 3", || Err(anyhow::anyhow!("busy")), || Ok(()));
+        let rolled_back = tx.rollback(failed.unwrap_err());
+
+        assert!(rolled_back.complete);
+        assert_eq!(rolled_back.error.to_string(), "busy, rolled back");
+        assert_eq!(
+            *log.borrow(),
+            [
+                "apply 0", "apply 1", "apply 2", "undo 2", "undo 1", "undo 0"
+            ]
+        );
+    }
+
+    #[test]
+    fn names_steps_that_could_not_be_undone() {
+        let mut tx = Transaction::new();
+        tx.step("policy0", || Ok(()), || Ok(())).unwrap();
+        tx.step("policy1", || Ok(()), || Err(anyhow::anyhow!("gone")))
+            .unwrap();
+        let rolled_back = tx.rollback(anyhow::anyhow!("policy2 is read-only"));
+        assert!(!rolled_back.complete);
+        assert_eq!(
+            rolled_back.error.to_string(),
+            "policy2 is read-only, could not roll back policy1"
+        );
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let undone = RefCell::new(false);
+        let mut tx = Transaction::new();
+        let undo = || {
+            *undone.borrow_mut() = true;
+            Ok(())
+        };
+        tx.step("step", || Ok(()), undo).unwrap();
+        tx.commit();
+        assert!(!*undone.borrow());
+    }
+}
@@ -2,12 +2,32 @@ use std::os::fd::AsFd;
 
 use nix::unistd;
 
-pub const UDS_FILENAME: &'static str = "gaimoded_sock";
+use crate::{paths::SystemPaths, proc_events::ProcEvent};
+
+// Members of this group may connect to the socket
+pub const UDS_GROUP: &str = "gaimode";
+
+// Optimizer answers every command through this
+pub type Reply = tokio::sync::oneshot::Sender<gaiproto::Response>;
+// Batches get one response per PID, in request order
+pub type BatchReply = tokio::sync::oneshot::Sender<Vec<gaiproto::PidResult>>;
 
 pub enum Commands {
-    OptimizeProcess(nix::unistd::Pid),
-    ResetProcess(nix::unistd::Pid),
-    ResetAll,
+    // The pidfd is set when the client attached one to the request, the name when it picked a profile
+    OptimizeProcess(
+        nix::unistd::Pid,
+        Option<std::os::fd::OwnedFd>,
+        Option<String>,
+        Reply,
+    ),
+    ResetProcess(nix::unistd::Pid, Reply),
+    // Limited to processes of one user unless None
+    ResetAll(Option<nix::unistd::Uid>, Reply),
+    Status(tokio::sync::oneshot::Sender<gaiproto::StatusReport>),
+    OptimizeBatch(Vec<nix::unistd::Pid>, BatchReply),
+    ResetBatch(Vec<nix::unistd::Pid>, BatchReply),
+    // From the kernel, not a client, nobody waits for an answer
+    ProcEvent(ProcEvent),
 }
 
 #[allow(dead_code)]
@@ -36,31 +56,57 @@ pub fn daemonize() -> anyhow::Result<()> {
 
                     nix::unistd::chdir("/")?; // chdir to / as daemon
 
-                    return Ok(());
+                    Ok(())
                 }
                 Ok(unistd::ForkResult::Parent { .. }) => {
                     unsafe { nix::libc::_exit(0) };
                 }
-                Err(why) => return Err(anyhow::anyhow!("Fork failed: {}", why)),
+                Err(why) => Err(anyhow::anyhow!("Fork failed: {}", why)),
             }
         }
-        Err(why) => {
-            return Err(anyhow::anyhow!("Fork failed: {}", why));
-        }
+        Err(why) => Err(anyhow::anyhow!("Fork failed: {}", why)),
     }
 }
 
 #[allow(dead_code)]
-pub fn tasks_in_process_n(pid: nix::unistd::Pid) -> anyhow::Result<u32> {
-    let path = format!("/proc/{}/task", pid.as_raw());
-    let dir = std::fs::read_dir(path)?;
+pub fn tasks_in_process_n(paths: &SystemPaths, pid: nix::unistd::Pid) -> anyhow::Result<u32> {
+    let dir = std::fs::read_dir(paths.tasks(pid))?;
     Ok(dir.count() as u32)
 }
 
-pub fn get_process_tasks(pid: nix::unistd::Pid) -> anyhow::Result<Vec<u32>> {
+// Every process currently in procfs
+pub fn list_processes(paths: &SystemPaths) -> anyhow::Result<Vec<nix::unistd::Pid>> {
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir(&paths.proc_root)? {
+        // Everything that isn't a number is not a process
+        if let Ok(pid) = entry?.file_name().to_string_lossy().parse::<i32>() {
+            pids.push(nix::unistd::Pid::from_raw(pid));
+        }
+    }
+    Ok(pids)
+}
+
+// Direct children of every thread of the process
+pub fn process_children(
+    paths: &SystemPaths,
+    pid: nix::unistd::Pid,
+) -> anyhow::Result<Vec<nix::unistd::Pid>> {
+    let mut res = Vec::new();
+    for tid in get_process_tasks(paths, pid)? {
+        // The thread may be gone already
+        let Ok(children) = std::fs::read_to_string(paths.children(pid, tid)) else {
+            continue;
+        };
+        for child in children.split_whitespace() {
+            res.push(nix::unistd::Pid::from_raw(child.parse()?));
+        }
+    }
+    Ok(res)
+}
+
+pub fn get_process_tasks(paths: &SystemPaths, pid: nix::unistd::Pid) -> anyhow::Result<Vec<u32>> {
     let mut res = Vec::new();
-    let path = format!("/proc/{}/task", pid.as_raw());
-    let dir_iter = std::fs::read_dir(path)?;
+    let dir_iter = std::fs::read_dir(paths.tasks(pid))?;
     for dir in dir_iter {
         let task_id = dir?.file_name().to_string_lossy().parse::<u32>()?;
         res.push(task_id);
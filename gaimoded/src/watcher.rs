@@ -0,0 +1,368 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use glob::Pattern;
+use nix::unistd::Pid;
+use tokio::{
+    sync::{mpsc::UnboundedSender, oneshot},
+    time::MissedTickBehavior,
+};
+
+use crate::{
+    cfg,
+    paths::SystemPaths,
+    proc_events::{ProcConnector, ProcEvent},
+    utils,
+};
+
+struct Rule {
+    name: String,
+    exe: Option<Pattern>,
+    comm: Option<Pattern>,
+    cmdline: Option<Pattern>,
+}
+
+impl Rule {
+    fn compile(rule: &cfg::MatchRule) -> anyhow::Result<Rule> {
+        let pattern = |field: &Option<String>| {
+            field
+                .as_deref()
+                .map(Pattern::new)
+                .transpose()
+                .map_err(|why| anyhow::anyhow!("Rule '{}': {}", rule.name, why))
+        };
+        let compiled = Rule {
+            name: rule.name.clone(),
+            exe: pattern(&rule.exe)?,
+            comm: pattern(&rule.comm)?,
+            cmdline: pattern(&rule.cmdline)?,
+        };
+        // It would match every process on the system
+        if compiled.exe.is_none() && compiled.comm.is_none() && compiled.cmdline.is_none() {
+            return Err(anyhow::anyhow!(
+                "Rule '{}' has nothing to match on",
+                rule.name
+            ));
+        }
+        Ok(compiled)
+    }
+
+    fn matches(&self, info: &ProcessInfo) -> bool {
+        let field = |pattern: &Option<Pattern>, value: &str| {
+            pattern.as_ref().is_none_or(|p| p.matches(value))
+        };
+        field(&self.exe, &info.exe)
+            && field(&self.comm, &info.comm)
+            && field(&self.cmdline, &info.cmdline)
+    }
+}
+
+// Watcher rules from the settings, ready for matching
+#[derive(Default)]
+pub struct Rules {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+    // Whether the watcher applies them, they can be tested either way
+    pub enabled: bool,
+}
+
+impl Rules {
+    pub fn compile(settings: &cfg::Watcher) -> anyhow::Result<Rules> {
+        Ok(Rules {
+            include: settings
+                .rules
+                .iter()
+                .map(Rule::compile)
+                .collect::<Result<_, _>>()?,
+            exclude: settings
+                .exclude
+                .iter()
+                .map(Rule::compile)
+                .collect::<Result<_, _>>()?,
+            enabled: settings.enabled,
+        })
+    }
+
+    pub fn evaluate(&self, info: &ProcessInfo) -> gaiproto::WatchVerdict {
+        if let Some(rule) = self.exclude.iter().find(|rule| rule.matches(info)) {
+            return gaiproto::WatchVerdict::Excluded(rule.name.clone());
+        }
+        match self.include.iter().find(|rule| rule.matches(info)) {
+            Some(rule) => gaiproto::WatchVerdict::Matched(rule.name.clone()),
+            None => gaiproto::WatchVerdict::NoMatch,
+        }
+    }
+}
+
+// What rules are matched against
+pub struct ProcessInfo {
+    pub exe: String,
+    pub comm: String,
+    // Arguments joined with spaces
+    pub cmdline: String,
+}
+
+impl ProcessInfo {
+    // Fails for kernel threads, they have no executable
+    pub fn read(paths: &SystemPaths, pid: Pid) -> anyhow::Result<ProcessInfo> {
+        let dir = paths.process(pid);
+        let exe = std::fs::read_link(dir.join("exe"))?;
+        let comm = std::fs::read_to_string(dir.join("comm"))?;
+        let cmdline = std::fs::read(dir.join("cmdline"))?;
+        let cmdline = cmdline
+            .split(|b| *b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(ProcessInfo {
+            exe: exe.to_string_lossy().into_owned(),
+            comm: comm.trim_end().to_owned(),
+            cmdline,
+        })
+    }
+}
+
+// Optimizes processes that match the rules as they show up
+pub struct Watcher {
+    rules: Arc<Rules>,
+    paths: SystemPaths,
+    scan_interval: Duration,
+    tx: UnboundedSender<utils::Commands>,
+    // Processes already looked at, so each is only considered once per exec
+    seen: HashSet<Pid>,
+}
+
+impl Watcher {
+    pub fn new(
+        rules: Arc<Rules>,
+        paths: SystemPaths,
+        settings: &cfg::Watcher,
+        tx: UnboundedSender<utils::Commands>,
+    ) -> Watcher {
+        Watcher {
+            rules,
+            paths,
+            scan_interval: Duration::from_secs(settings.scan_interval_secs.max(1)),
+            tx,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub async fn run(mut self) {
+        // Subscribed before the first scan so nothing started in between is missed
+        let connector = ProcConnector::open();
+        // Games started before the daemon count too
+        self.scan().await;
+
+        let mut connector = match connector {
+            Ok(connector) => connector,
+            Err(why) => {
+                tracing::warn!("Watcher has no process events, polling instead: {}", why);
+                return self.poll().await;
+            }
+        };
+        loop {
+            let events = match connector.next_events().await {
+                Ok(events) => events,
+                Err(why) if why.raw_os_error() == Some(libc::ENOBUFS) => {
+                    // Some execs were missed, catch up on everything
+                    self.scan().await;
+                    continue;
+                }
+                Err(why) => {
+                    tracing::error!("Watcher lost process events, polling instead: {}", why);
+                    return self.poll().await;
+                }
+            };
+            for event in events {
+                match event {
+                    // A new program, what it is only becomes known now
+                    ProcEvent::Exec { pid } => {
+                        self.seen.insert(pid);
+                        self.consider(pid).await;
+                    }
+                    ProcEvent::Exit { pid, tgid } if pid == tgid => {
+                        self.seen.remove(&pid);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    async fn poll(mut self) {
+        let mut interval = tokio::time::interval(self.scan_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.scan().await;
+        }
+    }
+
+    // Considers every process not looked at before
+    async fn scan(&mut self) {
+        let paths = self.paths.clone();
+        let pids = match tokio::task::spawn_blocking(move || utils::list_processes(&paths)).await {
+            Ok(Ok(pids)) => pids,
+            Ok(Err(why)) => {
+                tracing::error!("Could not list processes: {}", why);
+                return;
+            }
+            Err(why) => {
+                tracing::error!("Could not list processes: {}", why);
+                return;
+            }
+        };
+        let alive = pids.iter().copied().collect::<HashSet<_>>();
+        self.seen.retain(|pid| alive.contains(pid));
+        for pid in pids {
+            if self.seen.insert(pid) {
+                self.consider(pid).await;
+            }
+        }
+    }
+
+    async fn consider(&self, pid: Pid) {
+        if pid.as_raw() == std::process::id() as i32 {
+            return;
+        }
+        let (paths, rules) = (self.paths.clone(), self.rules.clone());
+        let checked = tokio::task::spawn_blocking(move || {
+            let info = ProcessInfo::read(&paths, pid).ok()?;
+            Some((rules.evaluate(&info), info.exe))
+        })
+        .await;
+        let Ok(Some((gaiproto::WatchVerdict::Matched(rule), exe))) = checked else {
+            return;
+        };
+
+        tracing::info!("Rule '{}' matched process {} ({})", rule, pid, exe);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if self
+            .tx
+            .send(utils::Commands::OptimizeProcess(pid, None, None, reply_tx))
+            .is_err()
+        {
+            return;
+        }
+        // Events keep coming while the optimizer works on it
+        tokio::spawn(async move {
+            if let Ok(gaiproto::Response::Error { message, .. }) = reply_rx.await {
+                tracing::warn!("Could not optimize watched process {}: {}", pid, message);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        name: &str,
+        exe: Option<&str>,
+        comm: Option<&str>,
+        cmdline: Option<&str>,
+    ) -> cfg::MatchRule {
+        cfg::MatchRule {
+            name: name.to_owned(),
+            exe: exe.map(str::to_owned),
+            comm: comm.map(str::to_owned),
+            cmdline: cmdline.map(str::to_owned),
+        }
+    }
+
+    fn rules(include: Vec<cfg::MatchRule>, exclude: Vec<cfg::MatchRule>) -> anyhow::Result<Rules> {
+        Rules::compile(&cfg::Watcher {
+            rules: include,
+            exclude,
+            ..cfg::Watcher::default()
+        })
+    }
+
+    fn process(exe: &str, comm: &str, cmdline: &str) -> ProcessInfo {
+        ProcessInfo {
+            exe: exe.to_owned(),
+            comm: comm.to_owned(),
+            cmdline: cmdline.to_owned(),
+        }
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let rules = rules(
+            vec![rule(
+                "steam games",
+                Some("*/steamapps/common/*"),
+                None,
+                None,
+            )],
+            vec![rule("anticheat", None, Some("*anticheat*"), None)],
+        )
+        .unwrap();
+
+        let game = process("/games/steamapps/common/cs2/cs2", "cs2", "cs2");
+        assert_eq!(
+            rules.evaluate(&game),
+            gaiproto::WatchVerdict::Matched("steam games".to_owned())
+        );
+        let anticheat = process(
+            "/games/steamapps/common/cs2/bin/anticheat",
+            "eac_anticheat",
+            "anticheat",
+        );
+        assert_eq!(
+            rules.evaluate(&anticheat),
+            gaiproto::WatchVerdict::Excluded("anticheat".to_owned())
+        );
+    }
+
+    #[test]
+    fn all_patterns_of_a_rule_have_to_match() {
+        let rules = rules(
+            vec![rule(
+                "java game",
+                Some("/usr/bin/java"),
+                None,
+                Some("* -jar *game.jar*"),
+            )],
+            Vec::new(),
+        )
+        .unwrap();
+
+        let game = process("/usr/bin/java", "java", "java -Xmx4G -jar /opt/game.jar");
+        assert_eq!(
+            rules.evaluate(&game),
+            gaiproto::WatchVerdict::Matched("java game".to_owned())
+        );
+        let ide = process("/usr/bin/java", "java", "java -jar /opt/ide.jar");
+        assert_eq!(rules.evaluate(&ide), gaiproto::WatchVerdict::NoMatch);
+        let other = process("/usr/local/bin/java", "java", "java -jar /opt/game.jar");
+        assert_eq!(rules.evaluate(&other), gaiproto::WatchVerdict::NoMatch);
+    }
+
+    #[test]
+    fn nothing_matches_by_default() {
+        let rules = rules(vec![rule("cs2", None, Some("cs2"), None)], Vec::new()).unwrap();
+        let shell = process("/usr/bin/bash", "bash", "bash");
+        assert_eq!(rules.evaluate(&shell), gaiproto::WatchVerdict::NoMatch);
+        assert_eq!(
+            Rules::default().evaluate(&shell),
+            gaiproto::WatchVerdict::NoMatch
+        );
+    }
+
+    #[test]
+    fn rejects_rule_without_patterns() {
+        let empty = rule("everything", None, None, None);
+        assert!(rules(vec![empty.clone()], Vec::new()).is_err());
+        assert!(rules(Vec::new(), vec![empty]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_pattern() {
+        let broken = rule("broken", Some("[cs2"), None, None);
+        let why = rules(vec![broken], Vec::new()).err().unwrap();
+        assert!(why.to_string().contains("broken"));
+    }
+}
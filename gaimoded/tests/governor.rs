@@ -0,0 +1,450 @@
+// Drives the optimizer against fake sysfs/procfs trees, so no real governor is touched
+use std::{path::Path, process::Child, time::Duration};
+
+use gaimoded::{
+    cfg, journal::Journal, load::LoadSampler, optimizer::Optimizer, paths::SystemPaths, utils,
+};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+const ORIGINAL_GOV: &str = "schedutil";
+
+struct FakeSystem {
+    _root: tempfile::TempDir,
+    paths: SystemPaths,
+    policies: usize,
+}
+
+impl FakeSystem {
+    fn new(policies: usize, available: &str) -> FakeSystem {
+        let root = tempfile::tempdir().unwrap();
+        let paths = SystemPaths::new(root.path().join("sys"), root.path().join("proc"));
+        let cpu_dir = paths.sys_root.join("devices/system/cpu");
+        for n in 0..policies {
+            let policy = cpu_dir.join(format!("cpufreq/policy{}", n));
+            write(
+                &policy.join("scaling_governor"),
+                &format!("{}\n", ORIGINAL_GOV),
+            );
+            write(
+                &policy.join("scaling_available_governors"),
+                &format!("{}\n", available),
+            );
+            write(
+                &cpu_dir.join(format!("cpu{}/topology/core_id", n)),
+                &format!("{}\n", n),
+            );
+        }
+        let stat = (0..policies)
+            .map(|n| format!("cpu{} 100 0 100 1000 0 0 0 0 0 0\n", n))
+            .collect::<String>();
+        write(&paths.stat(), &stat);
+        FakeSystem {
+            _root: root,
+            paths,
+            policies,
+        }
+    }
+
+    fn governors(&self) -> Vec<String> {
+        (0..self.policies)
+            .map(|n| {
+                let path = self.paths.sys_root.join(format!(
+                    "devices/system/cpu/cpufreq/policy{}/scaling_governor",
+                    n
+                ));
+                std::fs::read_to_string(path).unwrap().trim().to_owned()
+            })
+            .collect()
+    }
+
+    fn state_dir(&self) -> std::path::PathBuf {
+        self._root.path().join("state")
+    }
+
+    fn journal(&self) -> String {
+        std::fs::read_to_string(self.state_dir().join("journal")).unwrap()
+    }
+
+    fn set_governors(&self, governor: &str) {
+        for n in 0..self.policies {
+            let path = self.paths.sys_root.join(format!(
+                "devices/system/cpu/cpufreq/policy{}/scaling_governor",
+                n
+            ));
+            write(&path, &format!("{}\n", governor));
+        }
+    }
+
+    fn assert_governors(&self, expected: &str) {
+        assert_eq!(self.governors(), vec![expected.to_owned(); self.policies]);
+    }
+
+    // A real process to track, mirrored into the fake procfs with its main thread
+    fn spawn_target(&self) -> Child {
+        let child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let task = self
+            .paths
+            .tasks(pid_of(&child))
+            .join(child.id().to_string());
+        std::fs::create_dir_all(task).unwrap();
+        child
+    }
+
+    // Makes `child` show up as started by `parent`
+    fn set_children(&self, parent: &Child, children: &[&Child]) {
+        let children = children
+            .iter()
+            .map(|child| child.id().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let path = self.paths.children(pid_of(parent), parent.id());
+        write(&path, &format!("{} ", children));
+    }
+
+    // Only the governor knob is on, the others would act on the real process
+    fn spawn_optimizer(&self) -> Daemon {
+        let mut settings = cfg::Settings::default();
+        settings.niceness.enabled = false;
+        settings.ioniceness.enabled = false;
+        settings.cpu_affinity.enabled = false;
+
+        let (events, _) = broadcast::channel(64);
+        let loads = LoadSampler::spawn(&self.paths);
+        let journal = Journal::open(&self.state_dir()).unwrap();
+        let mut optimizer = Optimizer::new(
+            settings,
+            self.paths.clone(),
+            events.clone(),
+            loads,
+            Some(journal),
+        );
+        let capabilities = optimizer.capabilities();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { optimizer.run(rx, shutdown).await }
+        });
+        Daemon {
+            tx,
+            shutdown,
+            task,
+            events,
+            capabilities,
+        }
+    }
+}
+
+struct Daemon {
+    tx: mpsc::UnboundedSender<utils::Commands>,
+    shutdown: CancellationToken,
+    task: JoinHandle<()>,
+    events: broadcast::Sender<gaiproto::Event>,
+    capabilities: u32,
+}
+
+impl Daemon {
+    async fn optimize(&self, child: &Child) -> gaiproto::Response {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let command = utils::Commands::OptimizeProcess(pid_of(child), None, None, reply_tx);
+        self.tx.send(command).unwrap();
+        reply_rx.await.unwrap()
+    }
+
+    async fn reset(&self, child: &Child) -> gaiproto::Response {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let command = utils::Commands::ResetProcess(pid_of(child), reply_tx);
+        self.tx.send(command).unwrap();
+        reply_rx.await.unwrap()
+    }
+
+    async fn status(&self) -> gaiproto::StatusReport {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tx.send(utils::Commands::Status(reply_tx)).unwrap();
+        reply_rx.await.unwrap()
+    }
+
+    async fn stop(self) {
+        self.shutdown.cancel();
+        self.task.await.unwrap();
+    }
+}
+
+fn write(path: &Path, contents: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+fn pid_of(child: &Child) -> nix::unistd::Pid {
+    nix::unistd::Pid::from_raw(child.id() as i32)
+}
+
+fn assert_ok(response: gaiproto::Response) {
+    assert!(
+        matches!(response, gaiproto::Response::Ok(_)),
+        "unexpected response {:?}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn optimize_switches_and_reset_restores() {
+    let system = FakeSystem::new(2, "performance powersave schedutil");
+    let daemon = system.spawn_optimizer();
+    assert_ne!(daemon.capabilities & gaiproto::CAP_CPU_GOVERNOR, 0);
+    let mut child = system.spawn_target();
+
+    assert_ok(daemon.optimize(&child).await);
+    system.assert_governors("performance");
+
+    assert_ok(daemon.reset(&child).await);
+    system.assert_governors(ORIGINAL_GOV);
+
+    daemon.stop().await;
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[tokio::test]
+async fn governors_switched_once_for_several_processes() {
+    let system = FakeSystem::new(2, "performance powersave schedutil");
+    let daemon = system.spawn_optimizer();
+    let mut first = system.spawn_target();
+    let mut second = system.spawn_target();
+
+    assert_ok(daemon.optimize(&first).await);
+    assert_ok(daemon.optimize(&second).await);
+    system.assert_governors("performance");
+
+    // Still one optimized process left
+    assert_ok(daemon.reset(&first).await);
+    system.assert_governors("performance");
+
+    assert_ok(daemon.reset(&second).await);
+    system.assert_governors(ORIGINAL_GOV);
+
+    daemon.stop().await;
+    for child in [&mut first, &mut second] {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn shutdown_restores_governors() {
+    let system = FakeSystem::new(2, "performance powersave schedutil");
+    let daemon = system.spawn_optimizer();
+    let mut events = daemon.events.subscribe();
+    let mut child = system.spawn_target();
+
+    assert_ok(daemon.optimize(&child).await);
+    system.assert_governors("performance");
+
+    daemon.stop().await;
+    system.assert_governors(ORIGINAL_GOV);
+
+    // Every policy reports the switch and the switch back
+    let mut changes = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        if let gaiproto::Event::GovernorChanged { policy, governor } = event {
+            assert!(policy.starts_with(&*system.paths.sys_root.to_string_lossy()));
+            changes.push(governor);
+        }
+    }
+    assert_eq!(
+        changes,
+        ["performance", "performance", ORIGINAL_GOV, ORIGINAL_GOV]
+    );
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[tokio::test]
+async fn exited_process_restores_governors() {
+    let system = FakeSystem::new(1, "performance powersave schedutil");
+    let daemon = system.spawn_optimizer();
+    let mut child = system.spawn_target();
+
+    assert_ok(daemon.optimize(&child).await);
+    system.assert_governors("performance");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    // Picked up by the periodic liveness check
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while system.governors()[0] != ORIGINAL_GOV {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "governor never restored"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn missing_performance_governor_is_left_alone() {
+    let system = FakeSystem::new(2, "powersave schedutil");
+    let daemon = system.spawn_optimizer();
+    assert_eq!(daemon.capabilities & gaiproto::CAP_CPU_GOVERNOR, 0);
+    let mut child = system.spawn_target();
+
+    let response = daemon.optimize(&child).await;
+    assert!(
+        !matches!(response, gaiproto::Response::Ok(_)),
+        "unexpected response {:?}",
+        response
+    );
+    system.assert_governors(ORIGINAL_GOV);
+
+    daemon.stop().await;
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[tokio::test]
+async fn children_keep_governor_after_launcher_exits() {
+    let system = FakeSystem::new(1, "performance powersave schedutil");
+    let daemon = system.spawn_optimizer();
+    let mut launcher = system.spawn_target();
+    let mut game = system.spawn_target();
+    system.set_children(&launcher, &[&game]);
+
+    assert_ok(daemon.optimize(&launcher).await);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while daemon.status().await.processes.len() < 2 {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "child never followed"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    launcher.kill().unwrap();
+    launcher.wait().unwrap();
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let status = daemon.status().await;
+    assert_eq!(
+        status.processes.iter().map(|p| p.pid).collect::<Vec<_>>(),
+        vec![game.id() as i32]
+    );
+    system.assert_governors("performance");
+
+    game.kill().unwrap();
+    game.wait().unwrap();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while system.governors()[0] != ORIGINAL_GOV {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "governor never restored"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    daemon.stop().await;
+}
+
+#[tokio::test]
+async fn resetting_root_resets_children() {
+    let system = FakeSystem::new(1, "performance powersave schedutil");
+    let daemon = system.spawn_optimizer();
+    let mut launcher = system.spawn_target();
+    let mut game = system.spawn_target();
+    system.set_children(&launcher, &[&game]);
+
+    assert_ok(daemon.optimize(&launcher).await);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while daemon.status().await.processes.len() < 2 {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "child never followed"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    assert_ok(daemon.reset(&launcher).await);
+    assert!(daemon.status().await.processes.is_empty());
+    system.assert_governors(ORIGINAL_GOV);
+
+    daemon.stop().await;
+    for child in [&mut launcher, &mut game] {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn journal_restores_governors_after_crash() {
+    let system = FakeSystem::new(2, "performance powersave schedutil");
+    let daemon = system.spawn_optimizer();
+    let mut child = system.spawn_target();
+
+    assert_ok(daemon.optimize(&child).await);
+    let left_behind = system.journal();
+    assert_eq!(left_behind.matches(ORIGINAL_GOV).count(), 2);
+
+    assert_ok(daemon.reset(&child).await);
+    assert!(system.journal().is_empty());
+    daemon.stop().await;
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    // As if the daemon had been killed while the governors were switched
+    system.set_governors("performance");
+    write(&system.state_dir().join("journal"), &left_behind);
+    let mut journal = Journal::open(&system.state_dir()).unwrap();
+    assert_eq!(journal.replay().unwrap(), 2);
+    system.assert_governors(ORIGINAL_GOV);
+    assert!(system.journal().is_empty());
+}
+
+#[tokio::test]
+async fn unrestored_journal_entries_survive_optimize_and_reset() {
+    let system = FakeSystem::new(2, "performance powersave schedutil");
+    // Left over by a crash: policy0 is still switched, and a file that can't be written to
+    system.set_governors("performance");
+    let policy0 = system
+        .paths
+        .sys_root
+        .join("devices/system/cpu/cpufreq/policy0/scaling_governor");
+    let stuck = system.state_dir().join("stuck");
+    std::fs::create_dir_all(&stuck).unwrap();
+    write(
+        &system.state_dir().join("journal"),
+        &format!(
+            "{}\t{}\n{}\tpowersave\n",
+            policy0.display(),
+            ORIGINAL_GOV,
+            stuck.display()
+        ),
+    );
+    // As if replay had failed for both at the last start
+    let daemon = system.spawn_optimizer();
+    let mut child = system.spawn_target();
+
+    assert_ok(daemon.optimize(&child).await);
+    system.assert_governors("performance");
+    assert_ok(daemon.reset(&child).await);
+
+    // policy0 got its real original back, policy1 what it had when switched
+    assert_eq!(
+        system.governors(),
+        vec![ORIGINAL_GOV.to_owned(), "performance".to_owned()]
+    );
+    assert_eq!(
+        system.journal(),
+        format!("{}\tpowersave\n", stuck.display())
+    );
+    daemon.stop().await;
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
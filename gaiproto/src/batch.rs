@@ -0,0 +1,50 @@
+use crate::{DecodeError, Gaiproto, Response, payload::PayloadReader};
+
+// Outcome of one PID in a batch request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PidResult {
+    pub pid: i32,
+    pub response: Response,
+}
+
+pub(crate) fn encode_pids(buf: &mut Vec<u8>, pids: &[i32]) {
+    buf.extend_from_slice(&(pids.len() as u16).to_be_bytes());
+    for pid in pids {
+        buf.extend_from_slice(&pid.to_be_bytes());
+    }
+}
+
+pub(crate) fn decode_pids(reader: &mut PayloadReader) -> Result<Vec<i32>, DecodeError> {
+    let count = reader.read_u16()?;
+    let mut pids = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        pids.push(reader.read_i32()?);
+    }
+    Ok(pids)
+}
+
+// Each result carries a whole response packet: kind, u32 payload length, payload
+pub(crate) fn encode_results(buf: &mut Vec<u8>, results: &[PidResult]) {
+    buf.extend_from_slice(&(results.len() as u16).to_be_bytes());
+    for result in results {
+        let packet = result.response.encode();
+        buf.extend_from_slice(&result.pid.to_be_bytes());
+        buf.extend_from_slice(&packet.kind.to_be_bytes());
+        buf.extend_from_slice(&(packet.payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&packet.payload);
+    }
+}
+
+pub(crate) fn decode_results(reader: &mut PayloadReader) -> Result<Vec<PidResult>, DecodeError> {
+    let count = reader.read_u16()?;
+    let mut results = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let pid = reader.read_i32()?;
+        let kind = reader.read_u16()?;
+        let len = reader.read_u32()? as usize;
+        let payload = reader.read_bytes(len)?.to_vec();
+        let response = Response::decode(&Gaiproto::with_payload(kind, payload))?;
+        results.push(PidResult { pid, response });
+    }
+    Ok(results)
+}
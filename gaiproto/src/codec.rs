@@ -0,0 +1,257 @@
+use std::io::{Read, Write};
+
+use crate::{CodecError, DecodeError, Gaiproto, MAX_PACKET_SIZE, MIN_PACKET_SIZE};
+
+// Splits a byte stream into packets using the `size` field of the header.
+// Works with tokio (feature "tokio") and with blocking std streams.
+#[derive(Debug, Clone, Copy)]
+pub struct GaiprotoCodec {
+    max_frame_size: usize,
+}
+
+impl Default for GaiprotoCodec {
+    fn default() -> Self {
+        Self {
+            max_frame_size: MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl GaiprotoCodec {
+    pub fn new() -> GaiprotoCodec {
+        GaiprotoCodec::default()
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> GaiprotoCodec {
+        GaiprotoCodec {
+            max_frame_size: max_frame_size.max(MIN_PACKET_SIZE),
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    // Validates the size field of a header and returns the full frame length
+    fn frame_len(&self, header: &[u8]) -> Result<usize, DecodeError> {
+        let size = u32::from_be_bytes(header[0..4].try_into().expect("header has a u32 size"));
+        if (size as usize) < MIN_PACKET_SIZE {
+            return Err(DecodeError::SizeTooSmall { size });
+        }
+        if size as usize > self.max_frame_size {
+            return Err(DecodeError::PayloadTooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+        Ok(size as usize)
+    }
+
+    fn check_outgoing(&self, item: &Gaiproto) -> Result<(), DecodeError> {
+        if item.size as usize != MIN_PACKET_SIZE + item.payload.len() {
+            return Err(DecodeError::InvalidPayload {
+                kind: item.kind,
+                expected: (item.size as usize).saturating_sub(MIN_PACKET_SIZE),
+                got: item.payload.len(),
+            });
+        }
+        if item.size as usize > self.max_frame_size {
+            return Err(DecodeError::PayloadTooLarge {
+                size: item.size,
+                max: self.max_frame_size,
+            });
+        }
+        Ok(())
+    }
+
+    // Blocks until a whole packet was read
+    pub fn read_from<R: Read>(&self, reader: &mut R) -> Result<Gaiproto, CodecError> {
+        let mut buf = vec![0u8; MIN_PACKET_SIZE];
+        reader.read_exact(&mut buf)?;
+
+        let len = self.frame_len(&buf)?;
+        buf.resize(len, 0);
+        reader.read_exact(&mut buf[MIN_PACKET_SIZE..])?;
+
+        Ok(Gaiproto::decode_with_limit(&buf, self.max_frame_size)?)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W, item: Gaiproto) -> Result<(), CodecError> {
+        self.check_outgoing(&item)?;
+        writer.write_all(&item.convert_to_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio_util::codec::Decoder for GaiprotoCodec {
+    type Item = Gaiproto;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Gaiproto>, CodecError> {
+        if src.len() < MIN_PACKET_SIZE {
+            src.reserve(MIN_PACKET_SIZE - src.len());
+            return Ok(None);
+        }
+
+        let len = self.frame_len(&src[..MIN_PACKET_SIZE])?;
+        if src.len() < len {
+            src.reserve(len - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(len);
+        Ok(Some(Gaiproto::decode_with_limit(
+            &frame,
+            self.max_frame_size,
+        )?))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio_util::codec::Encoder<Gaiproto> for GaiprotoCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Gaiproto, dst: &mut bytes::BytesMut) -> Result<(), CodecError> {
+        self.check_outgoing(&item)?;
+        dst.reserve(item.size as usize);
+        dst.extend_from_slice(&item.size.to_be_bytes());
+        dst.extend_from_slice(&item.kind.to_be_bytes());
+        dst.extend_from_slice(&item.payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{K_RESET_ALL, K_RESET_PROCESS};
+
+    fn reset_process(pid: i32) -> Vec<u8> {
+        Gaiproto::with_payload(K_RESET_PROCESS, pid.to_be_bytes().to_vec()).convert_to_bytes()
+    }
+
+    #[test]
+    fn reads_packets_one_after_another() {
+        let mut bytes = reset_process(7);
+        bytes.extend(Gaiproto::with_payload(K_RESET_ALL, Vec::new()).convert_to_bytes());
+        let mut reader = bytes.as_slice();
+
+        let codec = GaiprotoCodec::new();
+        assert_eq!(codec.read_from(&mut reader).unwrap().payload, [0, 0, 0, 7]);
+        assert_eq!(codec.read_from(&mut reader).unwrap().kind, K_RESET_ALL);
+        assert!(matches!(
+            codec.read_from(&mut reader),
+            Err(CodecError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn read_fails_on_stream_ending_mid_packet() {
+        let bytes = reset_process(7);
+        let mut reader = &bytes[..bytes.len() - 1];
+        let Err(CodecError::Io(why)) = GaiprotoCodec::new().read_from(&mut reader) else {
+            panic!("a cut short packet was accepted");
+        };
+        assert_eq!(why.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_rejects_oversized_frame_before_reading_it() {
+        let mut bytes = 1024u32.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&K_RESET_ALL.to_be_bytes());
+        let result = GaiprotoCodec::with_max_frame_size(64).read_from(&mut bytes.as_slice());
+        assert!(matches!(
+            result,
+            Err(CodecError::Decode(DecodeError::PayloadTooLarge {
+                size: 1024,
+                max: 64
+            }))
+        ));
+    }
+
+    #[test]
+    fn write_rejects_size_not_matching_payload() {
+        let packet = Gaiproto::new(20, K_RESET_PROCESS, vec![0; 4]);
+        let result = GaiprotoCodec::new().write_to(&mut Vec::new(), packet);
+        assert!(matches!(
+            result,
+            Err(CodecError::Decode(DecodeError::InvalidPayload { .. }))
+        ));
+    }
+
+    #[test]
+    fn write_rejects_oversized_frame() {
+        let packet = Gaiproto::with_payload(K_RESET_PROCESS, vec![0; 64]);
+        let mut out = Vec::new();
+        let result = GaiprotoCodec::with_max_frame_size(64).write_to(&mut out, packet);
+        assert!(matches!(
+            result,
+            Err(CodecError::Decode(DecodeError::PayloadTooLarge { .. }))
+        ));
+        assert!(out.is_empty());
+    }
+
+    #[cfg(feature = "tokio")]
+    mod framed {
+        use bytes::BytesMut;
+        use tokio_util::codec::Decoder;
+
+        use super::*;
+
+        #[test]
+        fn waits_for_partial_frame() {
+            let bytes = reset_process(7);
+            let mut codec = GaiprotoCodec::new();
+            let mut buf = BytesMut::new();
+
+            // Byte by byte, nothing comes out before the last one
+            for byte in &bytes[..bytes.len() - 1] {
+                buf.extend_from_slice(&[*byte]);
+                assert!(codec.decode(&mut buf).unwrap().is_none());
+            }
+            buf.extend_from_slice(&bytes[bytes.len() - 1..]);
+            let packet = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(packet.payload, [0, 0, 0, 7]);
+            assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn leaves_next_frame_in_buffer() {
+            let mut buf = BytesMut::from(&reset_process(7)[..]);
+            buf.extend_from_slice(&reset_process(8)[..3]);
+            let mut codec = GaiprotoCodec::new();
+            assert_eq!(
+                codec.decode(&mut buf).unwrap().unwrap().payload,
+                [0, 0, 0, 7]
+            );
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+            assert_eq!(buf.len(), 3);
+        }
+
+        #[test]
+        fn rejects_oversized_frame_from_header() {
+            let mut buf = BytesMut::from(&1024u32.to_be_bytes()[..]);
+            buf.extend_from_slice(&K_RESET_ALL.to_be_bytes());
+            let result = GaiprotoCodec::with_max_frame_size(64).decode(&mut buf);
+            assert!(matches!(
+                result,
+                Err(CodecError::Decode(DecodeError::PayloadTooLarge {
+                    size: 1024,
+                    max: 64
+                }))
+            ));
+        }
+
+        #[test]
+        fn rejects_size_below_header() {
+            let mut buf = BytesMut::from(&5u32.to_be_bytes()[..]);
+            buf.extend_from_slice(&K_RESET_ALL.to_be_bytes());
+            let result = GaiprotoCodec::new().decode(&mut buf);
+            assert!(matches!(
+                result,
+                Err(CodecError::Decode(DecodeError::SizeTooSmall { size: 5 }))
+            ));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    // Less than MIN_PACKET_SIZE bytes were available
+    TruncatedHeader {
+        available: usize,
+    },
+    // Size field can't even cover the header
+    SizeTooSmall {
+        size: u32,
+    },
+    // Size field claims more bytes than were received
+    SizeExceedsBuffer {
+        size: u32,
+        available: usize,
+    },
+    // Size field is above the frame size limit
+    PayloadTooLarge {
+        size: u32,
+        max: usize,
+    },
+    UnknownKind(u16),
+    // Payload does not have the layout the kind requires
+    InvalidPayload {
+        kind: u16,
+        expected: usize,
+        got: usize,
+    },
+    // Payload has the right length but its contents make no sense
+    MalformedPayload {
+        kind: u16,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TruncatedHeader { available } => write!(
+                f,
+                "truncated header: got {} bytes, need at least {}",
+                available,
+                crate::MIN_PACKET_SIZE
+            ),
+            DecodeError::SizeTooSmall { size } => write!(
+                f,
+                "packet size {} is smaller than the header ({} bytes)",
+                size,
+                crate::MIN_PACKET_SIZE
+            ),
+            DecodeError::SizeExceedsBuffer { size, available } => write!(
+                f,
+                "packet size {} is larger than the {} bytes received",
+                size, available
+            ),
+            DecodeError::PayloadTooLarge { size, max } => {
+                write!(f, "packet size {} exceeds the maximum of {}", size, max)
+            }
+            DecodeError::UnknownKind(kind) => write!(f, "unknown packet kind {:#x}", kind),
+            DecodeError::InvalidPayload {
+                kind,
+                expected,
+                got,
+            } => write!(
+                f,
+                "invalid payload for kind {:#x}: expected {} bytes, got {}",
+                kind, expected, got
+            ),
+            DecodeError::MalformedPayload { kind, reason } => {
+                write!(f, "malformed payload for kind {:#x}: {}", kind, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    Decode(DecodeError),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(why) => write!(f, "I/O error: {}", why),
+            CodecError::Decode(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Io(why) => Some(why),
+            CodecError::Decode(why) => Some(why),
+        }
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(value: std::io::Error) -> Self {
+        CodecError::Io(value)
+    }
+}
+
+impl From<DecodeError> for CodecError {
+    fn from(value: DecodeError) -> Self {
+        CodecError::Decode(value)
+    }
+}
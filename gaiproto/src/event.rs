@@ -0,0 +1,63 @@
+use crate::{
+    DecodeError,
+    payload::{PayloadReader, put_str},
+};
+
+const TAG_PROCESS_OPTIMIZED: u8 = 0x1;
+const TAG_PROCESS_RESET: u8 = 0x2;
+const TAG_PROCESS_DIED: u8 = 0x3;
+const TAG_GOVERNOR_CHANGED: u8 = 0x4;
+
+// Things the daemon did, pushed to subscribed clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    ProcessOptimized { pid: i32 },
+    ProcessReset { pid: i32 },
+    // Tracked process exited and was dropped
+    ProcessDied { pid: i32 },
+    GovernorChanged { policy: String, governor: String },
+}
+
+impl Event {
+    pub(crate) fn encode_payload(&self, buf: &mut Vec<u8>) {
+        match self {
+            Event::ProcessOptimized { pid } => {
+                buf.push(TAG_PROCESS_OPTIMIZED);
+                buf.extend_from_slice(&pid.to_be_bytes());
+            }
+            Event::ProcessReset { pid } => {
+                buf.push(TAG_PROCESS_RESET);
+                buf.extend_from_slice(&pid.to_be_bytes());
+            }
+            Event::ProcessDied { pid } => {
+                buf.push(TAG_PROCESS_DIED);
+                buf.extend_from_slice(&pid.to_be_bytes());
+            }
+            Event::GovernorChanged { policy, governor } => {
+                buf.push(TAG_GOVERNOR_CHANGED);
+                put_str(buf, policy);
+                put_str(buf, governor);
+            }
+        }
+    }
+
+    pub(crate) fn decode_payload(reader: &mut PayloadReader) -> Result<Event, DecodeError> {
+        let event = match reader.read_u8()? {
+            TAG_PROCESS_OPTIMIZED => Event::ProcessOptimized {
+                pid: reader.read_i32()?,
+            },
+            TAG_PROCESS_RESET => Event::ProcessReset {
+                pid: reader.read_i32()?,
+            },
+            TAG_PROCESS_DIED => Event::ProcessDied {
+                pid: reader.read_i32()?,
+            },
+            TAG_GOVERNOR_CHANGED => Event::GovernorChanged {
+                policy: reader.read_str()?,
+                governor: reader.read_str()?,
+            },
+            _ => return Err(reader.malformed("unknown event")),
+        };
+        Ok(event)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{DecodeError, Gaiproto, K_HELLO, Knob, payload::PayloadReader};
+
+pub const PROTOCOL_VERSION: u16 = 2;
+// Oldest peer version both binaries still talk to
+pub const MIN_SUPPORTED_VERSION: u16 = 1;
+// Optimize requests name a settings profile, status reports the one of each process
+pub const PROFILES_VERSION: u16 = 2;
+
+pub const CAP_NICENESS: u32 = 1 << 0;
+pub const CAP_IONICENESS: u32 = 1 << 1;
+pub const CAP_CPU_AFFINITY: u32 = 1 << 2;
+pub const CAP_CPU_GOVERNOR: u32 = 1 << 3;
+
+impl Knob {
+    pub fn capability(&self) -> u32 {
+        match self {
+            Knob::Niceness => CAP_NICENESS,
+            Knob::IoNiceness => CAP_IONICENESS,
+            Knob::CpuAffinity => CAP_CPU_AFFINITY,
+            Knob::CpuGovernor => CAP_CPU_GOVERNOR,
+        }
+    }
+}
+
+// First packet of a connection, sent by both sides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    // Bitset of CAP_* the sender supports, clients send 0
+    pub capabilities: u32,
+}
+
+impl Hello {
+    pub fn new(capabilities: u32) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.version >= MIN_SUPPORTED_VERSION
+    }
+
+    // Version the rest of the conversation uses, the older of the two ends
+    pub fn negotiated(&self) -> u16 {
+        self.version.min(PROTOCOL_VERSION)
+    }
+
+    pub fn supports(&self, knob: Knob) -> bool {
+        self.capabilities & knob.capability() != 0
+    }
+
+    pub fn encode(&self) -> Gaiproto {
+        let mut payload = Vec::with_capacity(6);
+        payload.extend_from_slice(&self.version.to_be_bytes());
+        payload.extend_from_slice(&self.capabilities.to_be_bytes());
+        Gaiproto::with_payload(K_HELLO, payload)
+    }
+
+    pub fn decode(pkt: &Gaiproto) -> Result<Hello, DecodeError> {
+        if pkt.kind != K_HELLO {
+            return Err(DecodeError::UnknownKind(pkt.kind));
+        }
+        let mut reader = PayloadReader::new(pkt.kind, &pkt.payload);
+        let version = reader.read_u16()?;
+        let capabilities = reader.read_u32()?;
+        reader.finish()?;
+        Ok(Hello {
+            version,
+            capabilities,
+        })
+    }
+}
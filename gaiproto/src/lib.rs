@@ -1,3 +1,30 @@
+mod batch;
+mod codec;
+mod error;
+mod event;
+mod hello;
+mod message;
+mod payload;
+mod response;
+mod status;
+mod watch;
+
+pub use batch::PidResult;
+pub use codec::GaiprotoCodec;
+pub use error::{CodecError, DecodeError};
+pub use event::Event;
+pub use hello::{
+    CAP_CPU_AFFINITY, CAP_CPU_GOVERNOR, CAP_IONICENESS, CAP_NICENESS, Hello, MIN_SUPPORTED_VERSION,
+    PROFILES_VERSION, PROTOCOL_VERSION,
+};
+pub use message::Message;
+pub use response::{
+    E_BUSY, E_INTERNAL, E_INVALID_REQUEST, E_NOT_FOUND, E_OPTIMIZE_FAILED, E_PERMISSION_DENIED,
+    E_UNSUPPORTED_VERSION, Knob, KnobResult, Response,
+};
+pub use status::{GovernorStatus, ProcessStatus, StatusReport};
+pub use watch::{WatchReport, WatchVerdict};
+
 #[derive(Debug)]
 pub struct Gaiproto {
     pub size: u32,
@@ -5,11 +32,54 @@ pub struct Gaiproto {
     pub payload: Vec<u8>,
 }
 
+// Where the daemon listens unless told otherwise, both sides honor the env override
+pub const DEFAULT_SOCKET_PATH: &str = "/run/gaimoded/gaimoded.sock";
+pub const SOCKET_PATH_ENV: &str = "GAIMODE_SOCKET";
+
 pub const MIN_PACKET_SIZE: usize = 6;
+// Default limit, GaiprotoCodec can be configured with a different one
+pub const MAX_PACKET_SIZE: usize = 64 * 1024;
 
 pub const K_OPTIMIZE_PROCESS: u16 = 0x2;
 pub const K_RESET_PROCESS: u16 = 0x4;
 pub const K_RESET_ALL: u16 = 0x6;
+pub const K_RESPONSE_OK: u16 = 0x8;
+pub const K_RESPONSE_ERROR: u16 = 0xA;
+pub const K_RESPONSE_PARTIAL: u16 = 0xC;
+pub const K_HELLO: u16 = 0xE;
+pub const K_STATUS_REQUEST: u16 = 0x10;
+pub const K_STATUS_REPORT: u16 = 0x12;
+pub const K_SUBSCRIBE: u16 = 0x14;
+pub const K_EVENT: u16 = 0x16;
+pub const K_OPTIMIZE_BATCH: u16 = 0x18;
+pub const K_RESET_BATCH: u16 = 0x1A;
+pub const K_BATCH_RESULT: u16 = 0x1C;
+pub const K_OPTIMIZE_PIDFD: u16 = 0x1E;
+pub const K_WATCH_TEST: u16 = 0x20;
+pub const K_WATCH_REPORT: u16 = 0x22;
+
+pub fn is_known_kind(kind: u16) -> bool {
+    matches!(
+        kind,
+        K_OPTIMIZE_PROCESS
+            | K_RESET_PROCESS
+            | K_RESET_ALL
+            | K_RESPONSE_OK
+            | K_RESPONSE_ERROR
+            | K_RESPONSE_PARTIAL
+            | K_HELLO
+            | K_STATUS_REQUEST
+            | K_STATUS_REPORT
+            | K_SUBSCRIBE
+            | K_EVENT
+            | K_OPTIMIZE_BATCH
+            | K_RESET_BATCH
+            | K_BATCH_RESULT
+            | K_OPTIMIZE_PIDFD
+            | K_WATCH_TEST
+            | K_WATCH_REPORT
+    )
+}
 
 impl Gaiproto {
     pub fn new(size: u32, kind: u16, payload: Vec<u8>) -> Gaiproto {
@@ -19,43 +89,144 @@ impl Gaiproto {
             payload,
         }
     }
+    // Size is computed from the payload so it can't disagree with it
+    pub fn with_payload(kind: u16, payload: Vec<u8>) -> Gaiproto {
+        Gaiproto::new((MIN_PACKET_SIZE + payload.len()) as u32, kind, payload)
+    }
     pub fn convert_to_bytes(self) -> Vec<u8> {
         self.into()
     }
-    pub fn from_bytes(bytes: Vec<u8>) -> Gaiproto {
-        bytes.into()
+
+    // Parses a single packet from the start of `bytes`, trailing bytes are ignored
+    pub fn decode(bytes: &[u8]) -> Result<Gaiproto, DecodeError> {
+        Gaiproto::decode_with_limit(bytes, MAX_PACKET_SIZE)
+    }
+
+    pub fn decode_with_limit(bytes: &[u8], max_size: usize) -> Result<Gaiproto, DecodeError> {
+        if bytes.len() < MIN_PACKET_SIZE {
+            return Err(DecodeError::TruncatedHeader {
+                available: bytes.len(),
+            });
+        }
+
+        let (size_bytes, cursor) = bytes.split_at(std::mem::size_of::<u32>());
+        let size = u32::from_be_bytes(size_bytes.try_into().expect("split at u32 size"));
+        let (kind_bytes, cursor) = cursor.split_at(std::mem::size_of::<u16>());
+        let kind = u16::from_be_bytes(kind_bytes.try_into().expect("split at u16 size"));
+
+        if (size as usize) < MIN_PACKET_SIZE {
+            return Err(DecodeError::SizeTooSmall { size });
+        }
+        if size as usize > max_size {
+            return Err(DecodeError::PayloadTooLarge {
+                size,
+                max: max_size,
+            });
+        }
+        if size as usize > bytes.len() {
+            return Err(DecodeError::SizeExceedsBuffer {
+                size,
+                available: bytes.len(),
+            });
+        }
+        if !is_known_kind(kind) {
+            return Err(DecodeError::UnknownKind(kind));
+        }
+
+        let payload = cursor[..size as usize - MIN_PACKET_SIZE].to_owned();
+        Ok(Gaiproto {
+            size,
+            kind,
+            payload,
+        })
     }
 }
 
-impl Into<Vec<u8>> for Gaiproto {
-    fn into(self) -> Vec<u8> {
-        let mut res = Vec::new();
-        res.reserve(std::mem::size_of::<u32>() + std::mem::size_of::<u16>() + self.payload.len());
-        res.extend_from_slice(&self.size.to_be_bytes());
-        res.extend_from_slice(&self.kind.to_be_bytes());
-        res.extend_from_slice(&self.payload);
+impl From<Gaiproto> for Vec<u8> {
+    fn from(value: Gaiproto) -> Self {
+        let mut res = Vec::with_capacity(
+            std::mem::size_of::<u32>() + std::mem::size_of::<u16>() + value.payload.len(),
+        );
+        res.extend_from_slice(&value.size.to_be_bytes());
+        res.extend_from_slice(&value.kind.to_be_bytes());
+        res.extend_from_slice(&value.payload);
         res
     }
 }
 
-impl From<Vec<u8>> for Gaiproto {
-    fn from(value: Vec<u8>) -> Self {
-        let cursor = &value[0..];
+impl TryFrom<&[u8]> for Gaiproto {
+    type Error = DecodeError;
 
-        let size = u32::from_be_bytes(cursor[0..std::mem::size_of::<u32>()].try_into().unwrap());
-        let cursor = &cursor[std::mem::size_of_val(&size)..];
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Gaiproto::decode(value)
+    }
+}
 
-        let kind = u16::from_be_bytes(cursor[0..std::mem::size_of::<u16>()].try_into().unwrap());
-        let cursor = &cursor[std::mem::size_of_val(&kind)..];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let payload_size: usize =
-            size as usize - (std::mem::size_of_val(&size) + std::mem::size_of_val(&kind));
-        let payload = cursor[0..payload_size].to_owned();
+    fn header(size: u32, kind: u16) -> Vec<u8> {
+        let mut bytes = size.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&kind.to_be_bytes());
+        bytes
+    }
 
-        Self {
-            size,
-            kind,
-            payload,
-        }
+    #[test]
+    fn decodes_packet_and_ignores_trailing_bytes() {
+        let mut bytes =
+            Gaiproto::with_payload(K_RESET_PROCESS, vec![0, 0, 0, 7]).convert_to_bytes();
+        bytes.extend_from_slice(&[0xFF, 0xFF]);
+        let packet = Gaiproto::decode(&bytes).unwrap();
+        assert_eq!(packet.size, 10);
+        assert_eq!(packet.kind, K_RESET_PROCESS);
+        assert_eq!(packet.payload, [0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        assert_eq!(
+            Gaiproto::decode(&[0, 0, 0, 6, 0]).unwrap_err(),
+            DecodeError::TruncatedHeader { available: 5 }
+        );
+    }
+
+    #[test]
+    fn rejects_size_below_header() {
+        assert_eq!(
+            Gaiproto::decode(&header(5, K_RESET_ALL)).unwrap_err(),
+            DecodeError::SizeTooSmall { size: 5 }
+        );
+    }
+
+    #[test]
+    fn rejects_size_beyond_received_bytes() {
+        let mut bytes = header(10, K_RESET_PROCESS);
+        bytes.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            Gaiproto::decode(&bytes).unwrap_err(),
+            DecodeError::SizeExceedsBuffer {
+                size: 10,
+                available: 8
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_size_over_limit() {
+        let bytes = Gaiproto::with_payload(K_OPTIMIZE_BATCH, vec![0; 10]).convert_to_bytes();
+        assert_eq!(
+            Gaiproto::decode_with_limit(&bytes, 12).unwrap_err(),
+            DecodeError::PayloadTooLarge { size: 16, max: 12 }
+        );
+        assert!(Gaiproto::decode_with_limit(&bytes, 16).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_kind() {
+        assert_eq!(
+            Gaiproto::decode(&header(6, 0x3)).unwrap_err(),
+            DecodeError::UnknownKind(0x3)
+        );
     }
 }
@@ -0,0 +1,434 @@
+use crate::{
+    DecodeError, Event, Gaiproto, Hello, K_BATCH_RESULT, K_EVENT, K_HELLO, K_OPTIMIZE_BATCH,
+    K_OPTIMIZE_PIDFD, K_OPTIMIZE_PROCESS, K_RESET_ALL, K_RESET_BATCH, K_RESET_PROCESS,
+    K_RESPONSE_ERROR, K_RESPONSE_OK, K_RESPONSE_PARTIAL, K_STATUS_REPORT, K_STATUS_REQUEST,
+    K_SUBSCRIBE, K_WATCH_REPORT, K_WATCH_TEST, PROFILES_VERSION, PROTOCOL_VERSION, PidResult,
+    Response, StatusReport, WatchReport, batch, payload::PayloadReader,
+};
+
+// Every packet that can travel over the socket. Sizes are derived from the payload,
+// so new commands only need a kind constant and a variant here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Hello(Hello),
+    // `profile` names a settings profile, None lets the daemon pick one
+    OptimizeProcess { pid: i32, profile: Option<String> },
+    ResetProcess { pid: i32 },
+    ResetAll,
+    Response(Response),
+    StatusRequest,
+    Status(StatusReport),
+    // Turns the connection into a stream of Event messages
+    Subscribe,
+    Event(Event),
+    // Applied by the daemon in one go, answered with BatchResult
+    OptimizeBatch { pids: Vec<i32> },
+    ResetBatch { pids: Vec<i32> },
+    BatchResult(Vec<PidResult>),
+    // Same as OptimizeProcess, but a pidfd for `pid` is attached as SCM_RIGHTS
+    OptimizeProcessFd { pid: i32, profile: Option<String> },
+    // Asks which watcher rule `pid` falls under, answered with WatchReport
+    WatchRulesTest { pid: i32 },
+    WatchReport(WatchReport),
+}
+
+impl Message {
+    pub fn encode(&self) -> Gaiproto {
+        self.encode_for(PROTOCOL_VERSION)
+    }
+
+    // Leaves out what a peer speaking `version` does not know about
+    pub fn encode_for(&self, version: u16) -> Gaiproto {
+        match self {
+            Message::Hello(hello) => hello.encode(),
+            Message::OptimizeProcess { pid, profile } => {
+                Gaiproto::with_payload(K_OPTIMIZE_PROCESS, optimize_payload(*pid, profile, version))
+            }
+            Message::ResetProcess { pid } => {
+                Gaiproto::with_payload(K_RESET_PROCESS, pid.to_be_bytes().to_vec())
+            }
+            Message::ResetAll => Gaiproto::with_payload(K_RESET_ALL, Vec::new()),
+            Message::Response(response) => response.encode(),
+            Message::StatusRequest => Gaiproto::with_payload(K_STATUS_REQUEST, Vec::new()),
+            Message::Status(report) => {
+                let mut payload = Vec::new();
+                report.encode_payload(&mut payload, version);
+                Gaiproto::with_payload(K_STATUS_REPORT, payload)
+            }
+            Message::Subscribe => Gaiproto::with_payload(K_SUBSCRIBE, Vec::new()),
+            Message::Event(event) => {
+                let mut payload = Vec::new();
+                event.encode_payload(&mut payload);
+                Gaiproto::with_payload(K_EVENT, payload)
+            }
+            Message::OptimizeBatch { pids } => {
+                let mut payload = Vec::new();
+                batch::encode_pids(&mut payload, pids);
+                Gaiproto::with_payload(K_OPTIMIZE_BATCH, payload)
+            }
+            Message::ResetBatch { pids } => {
+                let mut payload = Vec::new();
+                batch::encode_pids(&mut payload, pids);
+                Gaiproto::with_payload(K_RESET_BATCH, payload)
+            }
+            Message::BatchResult(results) => {
+                let mut payload = Vec::new();
+                batch::encode_results(&mut payload, results);
+                Gaiproto::with_payload(K_BATCH_RESULT, payload)
+            }
+            Message::OptimizeProcessFd { pid, profile } => {
+                Gaiproto::with_payload(K_OPTIMIZE_PIDFD, optimize_payload(*pid, profile, version))
+            }
+            Message::WatchRulesTest { pid } => {
+                Gaiproto::with_payload(K_WATCH_TEST, pid.to_be_bytes().to_vec())
+            }
+            Message::WatchReport(report) => {
+                let mut payload = Vec::new();
+                report.encode_payload(&mut payload);
+                Gaiproto::with_payload(K_WATCH_REPORT, payload)
+            }
+        }
+    }
+
+    pub fn decode(pkt: &Gaiproto) -> Result<Message, DecodeError> {
+        Message::decode_for(pkt, PROTOCOL_VERSION)
+    }
+
+    // Reads a packet sent by a peer speaking `version`
+    pub fn decode_for(pkt: &Gaiproto, version: u16) -> Result<Message, DecodeError> {
+        let mut reader = PayloadReader::new(pkt.kind, &pkt.payload);
+        let message = match pkt.kind {
+            K_HELLO => return Ok(Message::Hello(Hello::decode(pkt)?)),
+            K_RESPONSE_OK | K_RESPONSE_ERROR | K_RESPONSE_PARTIAL => {
+                return Ok(Message::Response(Response::decode(pkt)?));
+            }
+            K_OPTIMIZE_PROCESS => Message::OptimizeProcess {
+                pid: reader.read_i32()?,
+                profile: read_profile(&mut reader, version)?,
+            },
+            K_RESET_PROCESS => Message::ResetProcess {
+                pid: reader.read_i32()?,
+            },
+            K_RESET_ALL => Message::ResetAll,
+            K_STATUS_REQUEST => Message::StatusRequest,
+            K_STATUS_REPORT => Message::Status(StatusReport::decode_payload(&mut reader, version)?),
+            K_SUBSCRIBE => Message::Subscribe,
+            K_EVENT => Message::Event(Event::decode_payload(&mut reader)?),
+            K_OPTIMIZE_BATCH => Message::OptimizeBatch {
+                pids: batch::decode_pids(&mut reader)?,
+            },
+            K_RESET_BATCH => Message::ResetBatch {
+                pids: batch::decode_pids(&mut reader)?,
+            },
+            K_BATCH_RESULT => Message::BatchResult(batch::decode_results(&mut reader)?),
+            K_OPTIMIZE_PIDFD => Message::OptimizeProcessFd {
+                pid: reader.read_i32()?,
+                profile: read_profile(&mut reader, version)?,
+            },
+            K_WATCH_TEST => Message::WatchRulesTest {
+                pid: reader.read_i32()?,
+            },
+            K_WATCH_REPORT => Message::WatchReport(WatchReport::decode_payload(&mut reader)?),
+            kind => return Err(DecodeError::UnknownKind(kind)),
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+}
+
+// The profile name fills the rest of the payload, so requests without one look as they always did.
+// Clients check the version before asking for a profile, older daemons would refuse the packet.
+fn optimize_payload(pid: i32, profile: &Option<String>, version: u16) -> Vec<u8> {
+    let mut payload = pid.to_be_bytes().to_vec();
+    if let Some(profile) = profile
+        && version >= PROFILES_VERSION
+    {
+        payload.extend_from_slice(profile.as_bytes());
+    }
+    payload
+}
+
+fn read_profile(reader: &mut PayloadReader, version: u16) -> Result<Option<String>, DecodeError> {
+    if version < PROFILES_VERSION {
+        return Ok(None);
+    }
+    let profile = reader.read_rest_str()?;
+    Ok((!profile.is_empty()).then_some(profile))
+}
+
+impl From<&Message> for Gaiproto {
+    fn from(value: &Message) -> Self {
+        value.encode()
+    }
+}
+
+impl TryFrom<&Gaiproto> for Message {
+    type Error = DecodeError;
+
+    fn try_from(value: &Gaiproto) -> Result<Self, Self::Error> {
+        Message::decode(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GovernorStatus, K_RESPONSE_OK, Knob, KnobResult, ProcessStatus, WatchVerdict};
+
+    fn round_trip(message: Message) {
+        let bytes = message.encode().convert_to_bytes();
+        let packet = Gaiproto::decode(&bytes).unwrap();
+        assert_eq!(packet.size as usize, bytes.len());
+        assert_eq!(Message::decode(&packet).unwrap(), message);
+    }
+
+    fn status_report() -> StatusReport {
+        StatusReport {
+            is_optimized: true,
+            governors: vec![GovernorStatus {
+                policy: "/sys/devices/system/cpu/cpufreq/policy0/scaling_governor".to_owned(),
+                governor: "schedutil".to_owned(),
+            }],
+            processes: vec![
+                ProcessStatus {
+                    pid: 42,
+                    niceness: Some(-10),
+                    ioniceness: Some(0),
+                    affinity: Some(vec![0, 2, 3]),
+                    pinned_cpu: Some(2),
+                    profile: Some("cs2".to_owned()),
+                },
+                ProcessStatus {
+                    pid: 43,
+                    niceness: None,
+                    ioniceness: None,
+                    affinity: None,
+                    pinned_cpu: None,
+                    profile: None,
+                },
+            ],
+        }
+    }
+
+    fn decode_payload(kind: u16, payload: Vec<u8>) -> Result<Message, DecodeError> {
+        Message::decode(&Gaiproto::with_payload(kind, payload))
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        round_trip(Message::Hello(Hello::new(0b1011)));
+        round_trip(Message::OptimizeProcess {
+            pid: 1234,
+            profile: None,
+        });
+        round_trip(Message::OptimizeProcess {
+            pid: 1234,
+            profile: Some("cs2".to_owned()),
+        });
+        round_trip(Message::OptimizeProcessFd {
+            pid: -1,
+            profile: Some("default".to_owned()),
+        });
+        round_trip(Message::ResetProcess { pid: 1234 });
+        round_trip(Message::ResetAll);
+        round_trip(Message::StatusRequest);
+        round_trip(Message::Subscribe);
+        round_trip(Message::OptimizeBatch {
+            pids: vec![1, 2, 3],
+        });
+        round_trip(Message::ResetBatch { pids: Vec::new() });
+        round_trip(Message::WatchRulesTest { pid: 99 });
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        round_trip(Message::Response(Response::Ok(vec![
+            Knob::Niceness,
+            Knob::CpuGovernor,
+        ])));
+        round_trip(Message::Response(Response::Error {
+            code: crate::E_NOT_FOUND,
+            message: "Process 7 has exited".to_owned(),
+        }));
+        round_trip(Message::Response(Response::Partial(vec![
+            KnobResult {
+                knob: Knob::IoNiceness,
+                error: None,
+            },
+            KnobResult {
+                knob: Knob::CpuAffinity,
+                error: Some("Invalid argument".to_owned()),
+            },
+        ])));
+    }
+
+    #[test]
+    fn reports_round_trip() {
+        round_trip(Message::Status(status_report()));
+        round_trip(Message::Status(StatusReport {
+            is_optimized: false,
+            governors: Vec::new(),
+            processes: Vec::new(),
+        }));
+        round_trip(Message::BatchResult(vec![
+            PidResult {
+                pid: 1,
+                response: Response::Ok(vec![Knob::Niceness]),
+            },
+            PidResult {
+                pid: 2,
+                response: Response::Error {
+                    code: crate::E_PERMISSION_DENIED,
+                    message: "not yours".to_owned(),
+                },
+            },
+        ]));
+        for verdict in [
+            WatchVerdict::NoMatch,
+            WatchVerdict::Matched("games".to_owned()),
+            WatchVerdict::Excluded("launchers".to_owned()),
+        ] {
+            round_trip(Message::WatchReport(WatchReport {
+                pid: 5,
+                exe: "/usr/bin/cs2".to_owned(),
+                comm: "cs2".to_owned(),
+                cmdline: "cs2 -novid".to_owned(),
+                verdict,
+                watcher_enabled: true,
+            }));
+        }
+    }
+
+    #[test]
+    fn events_round_trip() {
+        round_trip(Message::Event(Event::ProcessOptimized { pid: 1 }));
+        round_trip(Message::Event(Event::ProcessReset { pid: 2 }));
+        round_trip(Message::Event(Event::ProcessDied { pid: 3 }));
+        round_trip(Message::Event(Event::GovernorChanged {
+            policy: "policy0".to_owned(),
+            governor: "performance".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn older_peers_get_no_profiles() {
+        let v1 = PROFILES_VERSION - 1;
+        let packet = Message::Status(status_report()).encode_for(v1);
+        let Message::Status(report) = Message::decode_for(&packet, v1).unwrap() else {
+            panic!("not a status report");
+        };
+        assert!(report.processes.iter().all(|p| p.profile.is_none()));
+        // The layout differs, a current peer can't read it
+        assert!(Message::decode(&packet).is_err());
+
+        let request = Message::OptimizeProcess {
+            pid: 7,
+            profile: Some("cs2".to_owned()),
+        };
+        assert_eq!(request.encode_for(v1).payload, 7i32.to_be_bytes());
+        // A profile sent to an older daemon is not part of its layout
+        assert!(Message::decode_for(&request.encode(), v1).is_err());
+    }
+
+    #[test]
+    fn rejects_short_payload() {
+        assert_eq!(
+            decode_payload(K_RESET_PROCESS, vec![0, 0, 7]).unwrap_err(),
+            DecodeError::InvalidPayload {
+                kind: K_RESET_PROCESS,
+                expected: 4,
+                got: 3
+            }
+        );
+        // Claims three PIDs, carries one
+        let mut pids = 3u16.to_be_bytes().to_vec();
+        pids.extend_from_slice(&1i32.to_be_bytes());
+        assert!(matches!(
+            decode_payload(K_OPTIMIZE_BATCH, pids),
+            Err(DecodeError::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        assert!(matches!(
+            decode_payload(K_RESET_PROCESS, vec![0, 0, 0, 7, 0]),
+            Err(DecodeError::MalformedPayload { .. })
+        ));
+        assert!(matches!(
+            decode_payload(K_STATUS_REQUEST, vec![0]),
+            Err(DecodeError::MalformedPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        let malformed = |kind, payload| {
+            matches!(
+                decode_payload(kind, payload),
+                Err(DecodeError::MalformedPayload { .. })
+            )
+        };
+        // Knob 0x9 does not exist
+        assert!(malformed(K_RESPONSE_OK, vec![0x1, 0x9]));
+        assert!(malformed(K_EVENT, vec![0x7, 0, 0, 0, 1]));
+        // Option flags are 0 or 1
+        assert!(malformed(K_STATUS_REPORT, vec![2, 0, 0, 0, 0]));
+
+        let mut report = Vec::new();
+        WatchReport {
+            pid: 5,
+            exe: String::new(),
+            comm: String::new(),
+            cmdline: String::new(),
+            verdict: WatchVerdict::NoMatch,
+            watcher_enabled: false,
+        }
+        .encode_payload(&mut report);
+        let verdict = report.len() - 2;
+        report[verdict] = 0x7;
+        assert!(malformed(K_WATCH_REPORT, report));
+    }
+
+    #[test]
+    fn rejects_invalid_strings() {
+        let event = Event::GovernorChanged {
+            policy: "p0".to_owned(),
+            governor: String::new(),
+        };
+        let mut payload = Message::Event(event).encode().payload;
+        // Tag and length come first
+        payload[3..5].copy_from_slice(&[0xFF, 0xFE]);
+        assert!(matches!(
+            decode_payload(K_EVENT, payload),
+            Err(DecodeError::MalformedPayload { .. })
+        ));
+
+        let mut profile = 7i32.to_be_bytes().to_vec();
+        profile.push(0xFF);
+        assert!(matches!(
+            decode_payload(K_OPTIMIZE_PROCESS, profile),
+            Err(DecodeError::MalformedPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_batch_result_with_foreign_packet() {
+        let mut payload = 1u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(&7i32.to_be_bytes());
+        payload.extend_from_slice(&K_RESET_ALL.to_be_bytes());
+        payload.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(
+            decode_payload(K_BATCH_RESULT, payload).unwrap_err(),
+            DecodeError::UnknownKind(K_RESET_ALL)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_kind() {
+        assert_eq!(
+            decode_payload(0x3, Vec::new()).unwrap_err(),
+            DecodeError::UnknownKind(0x3)
+        );
+    }
+}
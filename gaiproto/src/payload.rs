@@ -0,0 +1,95 @@
+use crate::DecodeError;
+
+// Sequential big-endian reader over a packet payload
+pub(crate) struct PayloadReader<'a> {
+    kind: u16,
+    buf: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    pub(crate) fn new(kind: u16, buf: &'a [u8]) -> Self {
+        Self { kind, buf }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() < n {
+            return Err(DecodeError::InvalidPayload {
+                kind: self.kind,
+                expected: n,
+                got: self.buf.len(),
+            });
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn malformed(&self, reason: &'static str) -> DecodeError {
+        DecodeError::MalformedPayload {
+            kind: self.kind,
+            reason,
+        }
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes(
+            self.take(2)?.try_into().expect("took 2 bytes"),
+        ))
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(
+            self.take(4)?.try_into().expect("took 4 bytes"),
+        ))
+    }
+
+    pub(crate) fn read_i32(&mut self) -> Result<i32, DecodeError> {
+        Ok(i32::from_be_bytes(
+            self.take(4)?.try_into().expect("took 4 bytes"),
+        ))
+    }
+
+    pub(crate) fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        self.take(n)
+    }
+
+    // u16 length prefix followed by UTF-8 bytes
+    pub(crate) fn read_str(&mut self) -> Result<String, DecodeError> {
+        let len = self.read_u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| self.malformed("string is not valid UTF-8"))
+    }
+
+    // Everything that is left, as UTF-8
+    pub(crate) fn read_rest_str(&mut self) -> Result<String, DecodeError> {
+        let bytes = self.take(self.buf.len())?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| self.malformed("string is not valid UTF-8"))
+    }
+
+    pub(crate) fn finish(self) -> Result<(), DecodeError> {
+        if !self.buf.is_empty() {
+            return Err(self.malformed("trailing bytes after payload"));
+        }
+        Ok(())
+    }
+}
+
+// Cuts `s` to at most `max` bytes without splitting a character
+pub(crate) fn truncated(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+pub(crate) fn put_str(buf: &mut Vec<u8>, s: &str) {
+    // Strings longer than u16::MAX are cut, the protocol has no use for them
+    let bytes = truncated(s, u16::MAX as usize).as_bytes();
+    buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
@@ -0,0 +1,134 @@
+use crate::{
+    DecodeError, Gaiproto, K_RESPONSE_ERROR, K_RESPONSE_OK, K_RESPONSE_PARTIAL, MAX_PACKET_SIZE,
+    MIN_PACKET_SIZE,
+    payload::{PayloadReader, put_str, truncated},
+};
+
+// Error codes carried by K_RESPONSE_ERROR
+pub const E_INTERNAL: u32 = 0x1;
+pub const E_NOT_FOUND: u32 = 0x2;
+pub const E_OPTIMIZE_FAILED: u32 = 0x3;
+pub const E_INVALID_REQUEST: u32 = 0x4;
+pub const E_UNSUPPORTED_VERSION: u32 = 0x5;
+pub const E_PERMISSION_DENIED: u32 = 0x6;
+// Too many clients, trying again later may work
+pub const E_BUSY: u32 = 0x7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Knob {
+    Niceness = 0x1,
+    IoNiceness = 0x2,
+    CpuAffinity = 0x3,
+    CpuGovernor = 0x4,
+}
+
+impl Knob {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Knob::Niceness => "niceness",
+            Knob::IoNiceness => "I/O niceness",
+            Knob::CpuAffinity => "CPU affinity",
+            Knob::CpuGovernor => "CPU governor",
+        }
+    }
+}
+
+impl TryFrom<u8> for Knob {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x1 => Ok(Knob::Niceness),
+            0x2 => Ok(Knob::IoNiceness),
+            0x3 => Ok(Knob::CpuAffinity),
+            0x4 => Ok(Knob::CpuGovernor),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnobResult {
+    pub knob: Knob,
+    // None if the knob was applied
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    // Everything requested was done, lists the knobs that were applied
+    Ok(Vec<Knob>),
+    Error { code: u32, message: String },
+    // Some knobs were applied and some failed
+    Partial(Vec<KnobResult>),
+}
+
+impl Response {
+    pub fn encode(&self) -> Gaiproto {
+        let (kind, payload) = match self {
+            Response::Ok(knobs) => (K_RESPONSE_OK, knobs.iter().map(|k| *k as u8).collect()),
+            Response::Error { code, message } => {
+                // Message is the rest of the packet, keep it within MAX_PACKET_SIZE
+                let message = truncated(message, MAX_PACKET_SIZE - MIN_PACKET_SIZE - 4);
+                let mut payload = Vec::with_capacity(4 + message.len());
+                payload.extend_from_slice(&code.to_be_bytes());
+                payload.extend_from_slice(message.as_bytes());
+                (K_RESPONSE_ERROR, payload)
+            }
+            Response::Partial(results) => {
+                let mut payload = Vec::new();
+                payload.extend_from_slice(&(results.len() as u16).to_be_bytes());
+                for result in results {
+                    payload.push(result.knob as u8);
+                    match &result.error {
+                        None => payload.push(0),
+                        Some(why) => {
+                            payload.push(1);
+                            put_str(&mut payload, why);
+                        }
+                    }
+                }
+                (K_RESPONSE_PARTIAL, payload)
+            }
+        };
+        Gaiproto::with_payload(kind, payload)
+    }
+
+    pub fn decode(pkt: &Gaiproto) -> Result<Response, DecodeError> {
+        let mut reader = PayloadReader::new(pkt.kind, &pkt.payload);
+        let response = match pkt.kind {
+            K_RESPONSE_OK => {
+                let mut knobs = Vec::with_capacity(pkt.payload.len());
+                for _ in 0..pkt.payload.len() {
+                    let knob = reader.read_u8()?;
+                    knobs.push(Knob::try_from(knob).map_err(|_| reader.malformed("unknown knob"))?);
+                }
+                Response::Ok(knobs)
+            }
+            K_RESPONSE_ERROR => {
+                let code = reader.read_u32()?;
+                let message = reader.read_rest_str()?;
+                Response::Error { code, message }
+            }
+            K_RESPONSE_PARTIAL => {
+                let count = reader.read_u16()?;
+                let mut results = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let knob = Knob::try_from(reader.read_u8()?)
+                        .map_err(|_| reader.malformed("unknown knob"))?;
+                    let error = match reader.read_u8()? {
+                        0 => None,
+                        1 => Some(reader.read_str()?),
+                        _ => return Err(reader.malformed("unknown knob status")),
+                    };
+                    results.push(KnobResult { knob, error });
+                }
+                Response::Partial(results)
+            }
+            kind => return Err(DecodeError::UnknownKind(kind)),
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+}
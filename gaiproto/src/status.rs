@@ -0,0 +1,151 @@
+use crate::{
+    DecodeError, PROFILES_VERSION,
+    payload::{PayloadReader, put_str},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GovernorStatus {
+    // sysfs path of the policy's scaling_governor
+    pub policy: String,
+    // Governor that will be restored on reset
+    pub governor: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessStatus {
+    pub pid: i32,
+    // Saved values, None if the knob is disabled
+    pub niceness: Option<i32>,
+    pub ioniceness: Option<i32>,
+    pub affinity: Option<Vec<u32>>,
+    pub pinned_cpu: Option<u32>,
+    // Settings profile it was optimized with, None for the top level settings
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StatusReport {
+    pub is_optimized: bool,
+    pub governors: Vec<GovernorStatus>,
+    pub processes: Vec<ProcessStatus>,
+}
+
+fn put_opt_i32(buf: &mut Vec<u8>, value: Option<i32>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn put_opt_u32(buf: &mut Vec<u8>, value: Option<u32>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn read_flag(reader: &mut PayloadReader) -> Result<bool, DecodeError> {
+    match reader.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(reader.malformed("invalid option flag")),
+    }
+}
+
+impl StatusReport {
+    pub(crate) fn encode_payload(&self, buf: &mut Vec<u8>, version: u16) {
+        buf.push(self.is_optimized as u8);
+
+        buf.extend_from_slice(&(self.governors.len() as u16).to_be_bytes());
+        for gov in &self.governors {
+            put_str(buf, &gov.policy);
+            put_str(buf, &gov.governor);
+        }
+
+        buf.extend_from_slice(&(self.processes.len() as u16).to_be_bytes());
+        for process in &self.processes {
+            buf.extend_from_slice(&process.pid.to_be_bytes());
+            put_opt_i32(buf, process.niceness);
+            put_opt_i32(buf, process.ioniceness);
+            match &process.affinity {
+                Some(cpus) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&(cpus.len() as u16).to_be_bytes());
+                    for cpu in cpus {
+                        buf.extend_from_slice(&cpu.to_be_bytes());
+                    }
+                }
+                None => buf.push(0),
+            }
+            put_opt_u32(buf, process.pinned_cpu);
+            if version < PROFILES_VERSION {
+                continue;
+            }
+            match &process.profile {
+                Some(profile) => {
+                    buf.push(1);
+                    put_str(buf, profile);
+                }
+                None => buf.push(0),
+            }
+        }
+    }
+
+    pub(crate) fn decode_payload(
+        reader: &mut PayloadReader,
+        version: u16,
+    ) -> Result<StatusReport, DecodeError> {
+        let is_optimized = read_flag(reader)?;
+
+        let count = reader.read_u16()?;
+        let mut governors = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let policy = reader.read_str()?;
+            let governor = reader.read_str()?;
+            governors.push(GovernorStatus { policy, governor });
+        }
+
+        let count = reader.read_u16()?;
+        let mut processes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let pid = reader.read_i32()?;
+            let niceness = read_flag(reader)?.then(|| reader.read_i32()).transpose()?;
+            let ioniceness = read_flag(reader)?.then(|| reader.read_i32()).transpose()?;
+            let affinity = if read_flag(reader)? {
+                let n = reader.read_u16()?;
+                let mut cpus = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    cpus.push(reader.read_u32()?);
+                }
+                Some(cpus)
+            } else {
+                None
+            };
+            let pinned_cpu = read_flag(reader)?.then(|| reader.read_u32()).transpose()?;
+            let profile = match version >= PROFILES_VERSION {
+                true => read_flag(reader)?.then(|| reader.read_str()).transpose()?,
+                false => None,
+            };
+            processes.push(ProcessStatus {
+                pid,
+                niceness,
+                ioniceness,
+                affinity,
+                pinned_cpu,
+                profile,
+            });
+        }
+
+        Ok(StatusReport {
+            is_optimized,
+            governors,
+            processes,
+        })
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{
+    DecodeError,
+    payload::{PayloadReader, put_str},
+};
+
+const TAG_NO_MATCH: u8 = 0x0;
+const TAG_MATCHED: u8 = 0x1;
+const TAG_EXCLUDED: u8 = 0x2;
+
+// Outcome of checking a process against the watcher rules, carrying the rule's name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchVerdict {
+    NoMatch,
+    Matched(String),
+    // An opt-out rule matched, it wins over any match
+    Excluded(String),
+}
+
+// What the watcher saw of a process and what it would do with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchReport {
+    pub pid: i32,
+    pub exe: String,
+    pub comm: String,
+    pub cmdline: String,
+    pub verdict: WatchVerdict,
+    // Rules are only applied automatically while the watcher runs
+    pub watcher_enabled: bool,
+}
+
+impl WatchReport {
+    pub(crate) fn encode_payload(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.pid.to_be_bytes());
+        put_str(buf, &self.exe);
+        put_str(buf, &self.comm);
+        put_str(buf, &self.cmdline);
+        match &self.verdict {
+            WatchVerdict::NoMatch => buf.push(TAG_NO_MATCH),
+            WatchVerdict::Matched(rule) => {
+                buf.push(TAG_MATCHED);
+                put_str(buf, rule);
+            }
+            WatchVerdict::Excluded(rule) => {
+                buf.push(TAG_EXCLUDED);
+                put_str(buf, rule);
+            }
+        }
+        buf.push(self.watcher_enabled as u8);
+    }
+
+    pub(crate) fn decode_payload(reader: &mut PayloadReader) -> Result<WatchReport, DecodeError> {
+        let pid = reader.read_i32()?;
+        let exe = reader.read_str()?;
+        let comm = reader.read_str()?;
+        let cmdline = reader.read_str()?;
+        let verdict = match reader.read_u8()? {
+            TAG_NO_MATCH => WatchVerdict::NoMatch,
+            TAG_MATCHED => WatchVerdict::Matched(reader.read_str()?),
+            TAG_EXCLUDED => WatchVerdict::Excluded(reader.read_str()?),
+            _ => return Err(reader.malformed("unknown watch verdict")),
+        };
+        let watcher_enabled = match reader.read_u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(reader.malformed("invalid watcher flag")),
+        };
+        Ok(WatchReport {
+            pid,
+            exe,
+            comm,
+            cmdline,
+            verdict,
+            watcher_enabled,
+        })
+    }
+}